@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bitcoin::block::Header;
+use bitcoin::consensus::{deserialize, encode};
+use bitcoin::hashes::Hash;
+use bitcoin::pow::Work;
+use bitcoin::{BlockHash, CompactTarget, Network, Target};
+
+use crate::block::{to_u256, Block};
+use crate::source::{self, BlockSource, BoxFuture};
+use crate::types::U256;
+
+/// Read-only access to the `blk*.dat` files of a bitcoind `blocks/` directory.
+///
+/// Blocks are stored in the order they were received, not by height,
+/// so headers are scanned on open to build the best chain (by cumulative work) from genesis.
+/// With `BlkOptions::index`, only the records appended since the last open are scanned.
+/// Files are XOR-obfuscated with the key in `xor.dat` when present (Bitcoin Core 28+).
+///
+/// Each record is `[magic: 4][size: u32 LE][block: size]`, records are appended until
+/// the pre-allocated (zeroed) tail of the file.
+#[derive(Clone)]
+pub struct BlkFiles {
+    dir: PathBuf,
+    xor: [u8; 8],
+    /// Position of each block in the best chain, indexed by height.
+    chain: Arc<Vec<Position>>,
+}
+
+#[derive(Clone)]
+pub struct BlkOptions {
+    /// Read-only bitcoind `blocks/` directory.
+    pub dir: PathBuf,
+    /// Network of the blocks, a record with the magic of another network is rejected.
+    pub network: Network,
+    /// Optional writable file the scanned headers of `dir` are persisted to,
+    /// the next open resumes scanning after the last one.
+    pub index: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy)]
+struct Position {
+    hash: BlockHash,
+    file: u32,
+    offset: u64,
+    size: u32,
+}
+
+/// Scanned block header, as persisted in the index:
+/// `[hash: 32][prev_hash: 32][bits: u32 LE][file: u32 LE][offset: u64 LE][size: u32 LE]`.
+#[derive(Clone, Copy)]
+struct Record {
+    prev_hash: BlockHash,
+    bits: CompactTarget,
+    position: Position,
+}
+
+const RECORD_SIZE: usize = 84;
+
+impl Record {
+    fn work(&self) -> Work {
+        Target::from_compact(self.bits).to_work()
+    }
+
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0..32].copy_from_slice(self.position.hash.as_byte_array());
+        bytes[32..64].copy_from_slice(self.prev_hash.as_byte_array());
+        bytes[64..68].copy_from_slice(&self.bits.to_consensus().to_le_bytes());
+        bytes[68..72].copy_from_slice(&self.position.file.to_le_bytes());
+        bytes[72..80].copy_from_slice(&self.position.offset.to_le_bytes());
+        bytes[80..84].copy_from_slice(&self.position.size.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Record {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        Record {
+            prev_hash: BlockHash::from_byte_array(bytes[32..64].try_into().unwrap()),
+            bits: CompactTarget::from_consensus(u32_at(64)),
+            position: Position {
+                hash: BlockHash::from_byte_array(bytes[0..32].try_into().unwrap()),
+                file: u32_at(68),
+                offset: u64::from_le_bytes(bytes[72..80].try_into().unwrap()),
+                size: u32_at(80),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Decode(encode::Error),
+    /// The record at `offset` of `file` isn't of the configured network.
+    Magic {
+        file: u32,
+        offset: u64,
+    },
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<encode::Error> for Error {
+    fn from(err: encode::Error) -> Error {
+        Error::Decode(err)
+    }
+}
+
+impl BlkFiles {
+    pub fn open(options: &BlkOptions) -> Result<BlkFiles, Error> {
+        let dir = options.dir.clone();
+        let xor = match std::fs::read(dir.join("xor.dat")) {
+            Ok(bytes) => bytes
+                .try_into()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid xor.dat"))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => [0u8; 8],
+            Err(err) => return Err(err.into()),
+        };
+
+        let indexed = match &options.index {
+            Some(path) => read_index(path)?,
+            None => Vec::new(),
+        };
+        // Records are appended in file order, scanning resumes after the last one indexed
+        let (mut file, mut offset) = indexed
+            .last()
+            .map(|record| {
+                let position = record.position;
+                (position.file, position.offset + position.size as u64)
+            })
+            .unwrap_or((0, 0));
+
+        let magic = options.network.magic().to_bytes();
+        let mut scanned = Vec::new();
+        while let Some(reader) = open_blk(&dir, file)? {
+            if !scan(reader, xor, magic, file, offset, &mut scanned)? {
+                // Later files are scanned once the block is written, resuming from it
+                break;
+            }
+            file += 1;
+            offset = 0;
+        }
+        if let Some(path) = &options.index {
+            append_index(path, &scanned)?;
+        }
+
+        let headers: HashMap<BlockHash, Record> = indexed
+            .into_iter()
+            .chain(scanned)
+            .map(|record| (record.position.hash, record))
+            .collect();
+
+        Ok(BlkFiles {
+            dir,
+            xor,
+            chain: Arc::new(best_chain(&headers)),
+        })
+    }
+
+    /// Height of the last block of the best chain, `None` if there are no blocks.
+    pub fn tip(&self) -> Option<u64> {
+        (self.chain.len() as u64).checked_sub(1)
+    }
+
+    pub fn read(&self, height: u64) -> Result<Option<Block>, Error> {
+        let Some(position) = self.chain.get(height as usize) else {
+            return Ok(None);
+        };
+
+        let mut reader = open_blk(&self.dir, position.file)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "blk file removed"))?;
+        reader.seek(SeekFrom::Start(position.offset))?;
+        let mut bytes = vec![0u8; position.size as usize];
+        reader.read_exact(&mut bytes)?;
+        unxor(&mut bytes, &self.xor, position.offset);
+
+        let block: bitcoin::Block = deserialize(&bytes)?;
+        Ok(Some(Block::from_bitcoin(height, &block)))
+    }
+}
+
+/// Blocks are read on the blocking thread pool as file reads are synchronous.
+impl BlockSource for BlkFiles {
+    fn get_blockhash(&self, height: u64) -> BoxFuture<'_, Result<U256, source::Error>> {
+        Box::pin(async move {
+            let position = self
+                .chain
+                .get(height as usize)
+                .ok_or(source::Error::NotFound)?;
+            Ok(to_u256(position.hash))
+        })
+    }
+
+    fn get_block(&self, hash: U256, height: u64) -> BoxFuture<'_, Result<Block, source::Error>> {
+        let files = self.clone();
+        Box::pin(async move {
+            let block = tokio::task::spawn_blocking(move || files.read(height))
+                .await
+                .expect("Failed to join blk read")?
+                .filter(|block| block.hash == hash);
+            block.ok_or(source::Error::NotFound)
+        })
+    }
+
+    fn get_block_count(&self) -> BoxFuture<'_, Result<u64, source::Error>> {
+        Box::pin(async move { self.tip().ok_or(source::Error::NotFound) })
+    }
+}
+
+fn open_blk(dir: &Path, file: u32) -> io::Result<Option<BufReader<File>>> {
+    match File::open(dir.join(format!("blk{:05}.dat", file))) {
+        Ok(f) => Ok(Some(BufReader::new(f))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// XOR `bytes` read at `offset` of the file with the repeating obfuscation key.
+fn unxor(bytes: &mut [u8], xor: &[u8; 8], offset: u64) {
+    if xor == &[0u8; 8] {
+        return;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte ^= xor[((offset + i as u64) % 8) as usize];
+    }
+}
+
+/// Records persisted in the index at `path`, a partially written last record is truncated.
+fn read_index(path: &Path) -> io::Result<Vec<Record>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let len = bytes.len() - bytes.len() % RECORD_SIZE;
+    if len != bytes.len() {
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(len as u64)?;
+    }
+    Ok(bytes[..len]
+        .chunks(RECORD_SIZE)
+        .map(Record::from_bytes)
+        .collect())
+}
+
+fn append_index(path: &Path, records: &[Record]) -> io::Result<()> {
+    let bytes: Vec<u8> = records
+        .iter()
+        .flat_map(|record| record.to_bytes())
+        .collect();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&bytes)?;
+    file.sync_data()
+}
+
+/// Scan the records of `file` from `offset`, `false` if it stopped at a block still being written.
+fn scan(
+    mut reader: BufReader<File>,
+    xor: [u8; 8],
+    magic: [u8; 4],
+    file: u32,
+    mut offset: u64,
+    records: &mut Vec<Record>,
+) -> Result<bool, Error> {
+    let len = reader.get_ref().metadata()?.len();
+
+    // Record header (8) + block header (80)
+    while offset + 88 <= len {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut record = [0u8; 88];
+        reader.read_exact(&mut record)?;
+        unxor(&mut record, &xor, offset);
+
+        if record[0..4] == [0u8; 4] {
+            // Pre-allocated space, no more blocks in this file
+            break;
+        }
+        if record[0..4] != magic {
+            return Err(Error::Magic { file, offset });
+        }
+
+        let size = u32::from_le_bytes(record[4..8].try_into().unwrap());
+        if offset + 8 + size as u64 > len {
+            return Ok(false);
+        }
+        let header: Header = deserialize(&record[8..88])?;
+        let hash = header.block_hash();
+        let position = Position {
+            hash,
+            file,
+            offset: offset + 8,
+            size,
+        };
+        records.push(Record {
+            prev_hash: header.prev_blockhash,
+            bits: header.bits,
+            position,
+        });
+        offset += 8 + size as u64;
+    }
+
+    Ok(true)
+}
+
+/// Link the scanned headers from genesis and pick the chain with the most cumulative work,
+/// headers that can't be linked to genesis are ignored.
+fn best_chain(headers: &HashMap<BlockHash, Record>) -> Vec<Position> {
+    let mut children = HashMap::<BlockHash, Vec<BlockHash>>::new();
+    for (hash, record) in headers {
+        children.entry(record.prev_hash).or_default().push(*hash);
+    }
+
+    let mut tip: Option<(Work, BlockHash)> = None;
+    let mut stack: Vec<(Work, BlockHash)> = children
+        .get(&BlockHash::all_zeros())
+        .into_iter()
+        .flatten()
+        .map(|hash| (headers[hash].work(), *hash))
+        .collect();
+    while let Some((work, hash)) = stack.pop() {
+        match tip {
+            Some((tip_work, _)) if tip_work >= work => {}
+            _ => tip = Some((work, hash)),
+        }
+        for child in children.get(&hash).into_iter().flatten() {
+            stack.push((work + headers[child].work(), *child));
+        }
+    }
+
+    let mut chain = Vec::new();
+    let mut next = tip.map(|(_, hash)| hash);
+    while let Some(record) = next.and_then(|hash| headers.get(&hash)) {
+        chain.push(record.position);
+        next = Some(record.prev_hash);
+    }
+    chain.reverse();
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use bitcoin::consensus::serialize;
+    use tempfile::tempdir;
+
+    use crate::fixtures;
+
+    use super::*;
+
+    const REGTEST_MAGIC: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+
+    fn write_blk(path: &Path, blocks: &[&bitcoin::Block], xor: &[u8; 8]) {
+        let mut bytes = Vec::new();
+        for block in blocks {
+            let block = serialize(*block);
+            bytes.extend_from_slice(&REGTEST_MAGIC);
+            bytes.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&block);
+        }
+        // Pre-allocated tail
+        bytes.extend_from_slice(&[0u8; 64]);
+        unxor(&mut bytes, xor, 0);
+        fs::write(path, bytes).unwrap();
+    }
+
+    fn options(dir: &Path) -> BlkOptions {
+        BlkOptions {
+            dir: dir.to_path_buf(),
+            network: Network::Regtest,
+            index: None,
+        }
+    }
+
+    #[test]
+    fn read_best_chain() {
+        let chain = fixtures::regtest_chain(6);
+        // Stale block competing with height 3
+        let stale = fixtures::regtest_block(&chain[2], 3, 1);
+
+        let xor = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0];
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("xor.dat"), xor).unwrap();
+        // Out of height order and across files
+        write_blk(
+            &dir.path().join("blk00000.dat"),
+            &[&chain[0], &chain[2], &chain[1], &stale],
+            &xor,
+        );
+        write_blk(
+            &dir.path().join("blk00001.dat"),
+            &[&chain[4], &chain[3], &chain[5]],
+            &xor,
+        );
+
+        let files = BlkFiles::open(&options(dir.path())).unwrap();
+        assert_eq!(files.tip(), Some(5));
+        for (height, expected) in chain.iter().enumerate() {
+            let block = files.read(height as u64).unwrap().unwrap();
+            assert_eq!(block.height, height as u64);
+            assert_eq!(block.hash, to_u256(expected.block_hash()));
+            assert_eq!(block.tx[0].txid, to_u256(expected.txdata[0].compute_txid()));
+        }
+        assert!(files.read(6).unwrap().is_none());
+    }
+
+    #[test]
+    fn read_empty() {
+        let dir = tempdir().unwrap();
+        let files = BlkFiles::open(&options(dir.path())).unwrap();
+        assert_eq!(files.tip(), None);
+        assert!(files.read(0).unwrap().is_none());
+    }
+
+    #[test]
+    fn most_work() {
+        let chain = fixtures::regtest_chain(6);
+        // Shorter fork at difficulty 1, with more work than the regtest blocks above it
+        let mut fork = fixtures::regtest_block(&chain[2], 3, 1);
+        fork.header.bits = CompactTarget::from_consensus(0x1d00ffff);
+
+        let dir = tempdir().unwrap();
+        let blocks: Vec<_> = chain.iter().chain([&fork]).collect();
+        write_blk(&dir.path().join("blk00000.dat"), &blocks, &[0; 8]);
+
+        let files = BlkFiles::open(&options(dir.path())).unwrap();
+        assert_eq!(files.tip(), Some(3));
+        let block = files.read(3).unwrap().unwrap();
+        assert_eq!(block.hash, to_u256(fork.block_hash()));
+    }
+
+    #[test]
+    fn other_network() {
+        let chain = fixtures::regtest_chain(2);
+        let dir = tempdir().unwrap();
+        write_blk(
+            &dir.path().join("blk00000.dat"),
+            &[&chain[0], &chain[1]],
+            &[0; 8],
+        );
+
+        let options = BlkOptions {
+            network: Network::Bitcoin,
+            ..options(dir.path())
+        };
+        let result = BlkFiles::open(&options);
+        assert!(matches!(result, Err(Error::Magic { file: 0, offset: 0 })));
+    }
+
+    #[test]
+    fn resume_scan() {
+        let chain = fixtures::regtest_chain(7);
+        let dir = tempdir().unwrap();
+        let blk = |n: u32| dir.path().join(format!("blk{:05}.dat", n));
+        write_blk(&blk(0), &[&chain[0], &chain[1], &chain[2]], &[0; 8]);
+        write_blk(&blk(1), &[&chain[3], &chain[4], &chain[5]], &[0; 8]);
+
+        let index = tempdir().unwrap();
+        let options = BlkOptions {
+            index: Some(index.path().join("blk-index")),
+            ..options(dir.path())
+        };
+        assert_eq!(BlkFiles::open(&options).unwrap().tip(), Some(5));
+
+        // Indexed records aren't scanned again, only the ones appended after them
+        let len = fs::metadata(blk(0)).unwrap().len();
+        fs::write(blk(0), vec![0u8; len as usize]).unwrap();
+        write_blk(&blk(2), &[&chain[6]], &[0; 8]);
+        let files = BlkFiles::open(&options).unwrap();
+        assert_eq!(files.tip(), Some(6));
+        let block = files.read(6).unwrap().unwrap();
+        assert_eq!(block.hash, to_u256(chain[6].block_hash()));
+    }
+
+    #[test]
+    fn truncated_record() {
+        let chain = fixtures::regtest_chain(4);
+        let dir = tempdir().unwrap();
+        let blk = |n: u32| dir.path().join(format!("blk{:05}.dat", n));
+        write_blk(&blk(0), &[&chain[0], &chain[1], &chain[2]], &[0; 8]);
+        write_blk(&blk(1), &[&chain[3]], &[0; 8]);
+        // Last block of the file partially written, without the pre-allocated tail
+        let len = fs::metadata(blk(0)).unwrap().len();
+        let file = OpenOptions::new().write(true).open(blk(0)).unwrap();
+        file.set_len(len - 64 - 10).unwrap();
+
+        let index = tempdir().unwrap();
+        let options = BlkOptions {
+            index: Some(index.path().join("blk-index")),
+            ..options(dir.path())
+        };
+        assert_eq!(BlkFiles::open(&options).unwrap().tip(), Some(1));
+
+        // Scanned again once written
+        write_blk(&blk(0), &[&chain[0], &chain[1], &chain[2]], &[0; 8]);
+        let files = BlkFiles::open(&options).unwrap();
+        assert_eq!(files.tip(), Some(3));
+        let block = files.read(2).unwrap().unwrap();
+        assert_eq!(block.hash, to_u256(chain[2].block_hash()));
+    }
+}
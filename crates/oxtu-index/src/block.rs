@@ -0,0 +1,180 @@
+use bitcoin::consensus::encode;
+use bitcoin::hashes::Hash;
+
+use crate::db::Vout as Outpoint;
+use crate::rpc;
+use crate::types::{U128Decimal, U256};
+
+/// Block as consumed by `Db::push`,
+/// decoded from either the verbose JSON of `getblock` or the raw consensus encoded bytes.
+/// Only the fields needed for the index are kept.
+pub struct Block {
+    pub height: u64,
+    pub hash: U256,
+    /// `None` for the genesis block.
+    pub prev_hash: Option<U256>,
+    pub tx: Vec<Tx>,
+}
+
+pub struct Tx {
+    pub txid: U256,
+    pub vin: Vec<Vin>,
+    pub vout: Vec<Vout>,
+}
+
+pub struct Vin {
+    /// Output spent by this input, `None` for coinbase.
+    pub prevout: Option<Outpoint>,
+}
+
+pub struct Vout {
+    pub n: u32,
+    pub script: Vec<u8>,
+    pub value: U128Decimal,
+}
+
+/// Bitcoin amounts are in satoshis, 8 decimal places.
+pub(crate) const SATOSHI_SCALE: u8 = 8;
+
+impl Block {
+    /// Decode a raw consensus encoded block (`getblock` verbosity 0),
+    /// height is not part of the encoding so it must be provided.
+    pub fn from_raw(height: u64, bytes: &[u8]) -> Result<Block, encode::Error> {
+        let block: bitcoin::Block = encode::deserialize(bytes)?;
+        Ok(Block::from_bitcoin(height, &block))
+    }
+
+    pub fn from_bitcoin(height: u64, block: &bitcoin::Block) -> Block {
+        let prev_hash = Some(block.header.prev_blockhash)
+            .filter(|hash| *hash != bitcoin::BlockHash::all_zeros())
+            .map(to_u256);
+
+        let tx = block.txdata.iter().map(Tx::from_bitcoin).collect();
+
+        Block {
+            height,
+            hash: to_u256(block.block_hash()),
+            prev_hash,
+            tx,
+        }
+    }
+}
+
+impl Tx {
+    /// Decode a raw consensus encoded transaction (`getrawtransaction` verbosity 0).
+    pub fn from_raw(bytes: &[u8]) -> Result<Tx, encode::Error> {
+        let tx: bitcoin::Transaction = encode::deserialize(bytes)?;
+        Ok(Tx::from_bitcoin(&tx))
+    }
+
+    pub fn from_bitcoin(tx: &bitcoin::Transaction) -> Tx {
+        Tx {
+            txid: to_u256(tx.compute_txid()),
+            vin: tx
+                .input
+                .iter()
+                .map(|input| Vin {
+                    prevout: Some(input.previous_output)
+                        .filter(|prevout| !prevout.is_null())
+                        .map(|prevout| Outpoint {
+                            txid: to_u256(prevout.txid),
+                            n: prevout.vout,
+                        }),
+                })
+                .collect(),
+            vout: tx
+                .output
+                .iter()
+                .enumerate()
+                .map(|(n, output)| Vout {
+                    n: n as u32,
+                    script: output.script_pubkey.to_bytes(),
+                    value: U128Decimal(output.value.to_sat() as u128, SATOSHI_SCALE),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<rpc::Block> for Block {
+    fn from(block: rpc::Block) -> Self {
+        let tx = block.tx.into_iter().map(Tx::from).collect();
+
+        Block {
+            height: block.height,
+            hash: U256::from_hex(&block.hash),
+            prev_hash: block.previousblockhash.map(|hash| U256::from_hex(&hash)),
+            tx,
+        }
+    }
+}
+
+impl From<rpc::Tx> for Tx {
+    fn from(tx: rpc::Tx) -> Self {
+        Tx {
+            txid: U256::from_hex(&tx.txid),
+            vin: tx
+                .vin
+                .into_iter()
+                .map(|vin| Vin {
+                    prevout: vin.txid.map(|txid| Outpoint {
+                        txid: U256::from_hex(&txid),
+                        n: vin.vout.expect("Vout is missing when txid is present"),
+                    }),
+                })
+                .collect(),
+            vout: tx
+                .vout
+                .into_iter()
+                .map(|vout| Vout {
+                    n: vout.n,
+                    script: hex::decode(&vout.script_pub_key.hex).unwrap(),
+                    value: vout.value.into(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Hashes are displayed (and returned by the JSON-RPC) in reverse byte order,
+/// we store them in the displayed order so both formats are interchangeable.
+pub(crate) fn to_u256(hash: impl Hash<Bytes = [u8; 32]>) -> U256 {
+    let mut bytes = hash.to_byte_array();
+    bytes.reverse();
+    U256::from(bytes)
+}
+
+/// Inverse of `to_u256`.
+pub(crate) fn from_u256<H: Hash<Bytes = [u8; 32]>>(hash: U256) -> H {
+    let mut bytes: [u8; 32] = hash.into();
+    bytes.reverse();
+    H::from_byte_array(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::serialize;
+    use bitcoin::Network;
+
+    use super::*;
+
+    #[test]
+    fn from_raw_genesis() {
+        let bytes = serialize(&genesis_block(Network::Regtest));
+        let block = Block::from_raw(0, &bytes).unwrap();
+
+        assert_eq!(
+            block.hash,
+            U256::from_hex("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+        );
+        assert!(block.prev_hash.is_none());
+        assert_eq!(
+            block.tx[0].txid,
+            U256::from_hex("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+        );
+        assert!(block.tx[0].vin[0].prevout.is_none());
+        assert_eq!(block.tx[0].vout[0].value.0, 5_000_000_000);
+        assert_eq!(block.tx[0].vout[0].value.1, 8);
+    }
+}
@@ -0,0 +1,166 @@
+//! Online backups of the database: a checkpoint is a RocksDB checkpoint, consistent as of when it
+//! is taken while blocks keep being connected, its SST files are hard links into the database.
+//!
+//! A checkpoint directory holds the database in `db/`, then `manifest.json` written last,
+//! a checkpoint without a manifest is incomplete.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::{Db, SCHEMA_VERSION};
+
+const DB_DIR: &str = "db";
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Rocks(rocksdb::Error),
+    /// There is no block indexed yet.
+    Empty,
+    /// The target of a checkpoint or restore already exists.
+    Exists(PathBuf),
+    /// The checkpoint has no manifest.
+    Incomplete(PathBuf),
+    /// The checkpoint is of a newer schema version than this build supports.
+    UnknownVersion(u32),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<rocksdb::Error> for Error {
+    fn from(err: rocksdb::Error) -> Error {
+        Error::Rocks(err)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    /// Schema version of the database, see `SCHEMA_VERSION`.
+    pub version: u32,
+    pub height: u64,
+    pub hash: String,
+}
+
+/// Checkpoint `db` into `dir`, which must not exist yet.
+/// A failed checkpoint is removed, it can be retried in the same `dir`.
+pub fn create(db: &Db, dir: &Path) -> Result<Manifest, Error> {
+    if dir.exists() {
+        return Err(Error::Exists(dir.to_path_buf()));
+    }
+    if db.peek().is_none() {
+        return Err(Error::Empty);
+    }
+    fs::create_dir_all(dir)?;
+    let result = write(db, dir);
+    if result.is_err() {
+        let _ = fs::remove_dir_all(dir);
+    }
+    result
+}
+
+fn write(db: &Db, dir: &Path) -> Result<Manifest, Error> {
+    let path = dir.join(DB_DIR);
+    db.checkpoint(&path)?;
+
+    // Blocks may have been connected since `peek`, the tip is read from the checkpoint
+    let checkpoint = Db::open(path.to_str().unwrap());
+    let tip = checkpoint.peek().ok_or(Error::Empty)?;
+    let manifest = Manifest {
+        version: checkpoint.schema_version(),
+        height: tip.height,
+        hash: tip.hash.to_hex(),
+    };
+    drop(checkpoint);
+
+    let file = File::create(dir.join(MANIFEST))?;
+    serde_json::to_writer_pretty(file, &manifest).map_err(io::Error::from)?;
+    tracing::info!("Checkpoint: {:?}", &manifest);
+    Ok(manifest)
+}
+
+/// Restore the checkpoint in `dir` as the database at `path`, which must not exist yet.
+/// The database is copied next to `path` then renamed, an interrupted restore leaves `path` untouched.
+pub fn restore(dir: &Path, path: &Path) -> Result<Manifest, Error> {
+    let file = match File::open(dir.join(MANIFEST)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::Incomplete(dir.to_path_buf()))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let manifest: Manifest = serde_json::from_reader(file).map_err(io::Error::from)?;
+    if manifest.version > SCHEMA_VERSION {
+        return Err(Error::UnknownVersion(manifest.version));
+    }
+    if path.exists() {
+        return Err(Error::Exists(path.to_path_buf()));
+    }
+
+    let mut restoring = path.as_os_str().to_owned();
+    restoring.push(".restoring");
+    let restoring = PathBuf::from(restoring);
+    if restoring.exists() {
+        fs::remove_dir_all(&restoring)?;
+    }
+    fs::create_dir_all(&restoring)?;
+    for entry in fs::read_dir(dir.join(DB_DIR))? {
+        let entry = entry?;
+        fs::copy(entry.path(), restoring.join(entry.file_name()))?;
+    }
+    fs::rename(&restoring, path)?;
+    tracing::info!("Restored: {:?}", &manifest);
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::fixtures;
+
+    use super::*;
+
+    #[test]
+    fn create_restore() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path().join("data").to_str().unwrap());
+        for (height, block) in fixtures::regtest_chain(4).iter().enumerate() {
+            db.push(crate::block::Block::from_bitcoin(height as u64, block));
+        }
+        db.set_chain(crate::types::U256::from([7; 32]));
+
+        let checkpoint = dir.path().join("checkpoint");
+        let manifest = create(&db, &checkpoint).unwrap();
+        assert_eq!(manifest.height, 3);
+        assert!(matches!(create(&db, &checkpoint), Err(Error::Exists(_))));
+        // Connected after the checkpoint, not in it
+        let next = fixtures::regtest_chain(5).pop().unwrap();
+        db.push(crate::block::Block::from_bitcoin(4, &next));
+
+        let path = dir.path().join("restored");
+        restore(&checkpoint, &path).unwrap();
+        let restored = Db::open(path.to_str().unwrap());
+        let tip = restored.peek().unwrap();
+        assert_eq!(tip.height, 3);
+        assert_eq!(tip.hash.to_hex(), manifest.hash);
+        assert_eq!(restored.chain(), Some(crate::types::U256::from([7; 32])));
+        restored
+            .snapshot()
+            .verify(|violation| panic!("{}", violation));
+
+        fs::remove_file(checkpoint.join(MANIFEST)).unwrap();
+        let path = dir.path().join("incomplete");
+        assert!(matches!(
+            restore(&checkpoint, &path),
+            Err(Error::Incomplete(_))
+        ));
+    }
+}
@@ -0,0 +1,382 @@
+//! Bitcoin Core's compact serializations, as used in `dumptxoutset` snapshots and its chainstate.
+//!
+//! Reference:
+//! https://github.com/bitcoin/bitcoin/blob/master/src/compressor.cpp
+//! https://github.com/bitcoin/bitcoin/blob/master/src/serialize.h
+
+use std::io::{self, Read, Write};
+
+use bitcoin::secp256k1::PublicKey;
+
+/// Scripts larger than this are unspendable, Core stores them as a single `OP_RETURN`.
+pub(crate) const MAX_SCRIPT_SIZE: u64 = 10_000;
+/// Number of special script templates, larger sizes are raw scripts of `size - SPECIAL_SCRIPTS`.
+const SPECIAL_SCRIPTS: u64 = 6;
+
+const OP_DUP: u8 = 0x76;
+const OP_HASH160: u8 = 0xa9;
+const OP_EQUAL: u8 = 0x87;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_RETURN: u8 = 0x6a;
+
+/// Core's `VARINT`: MSB base-128 where each continuation byte is offset by one,
+/// not to be confused with the `CompactSize` of the consensus encoding.
+pub fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    u64::try_from(read_varint_u128(reader)?).map_err(|_| invalid_data("VARINT too large"))
+}
+
+/// `read_varint` of integers as wide as `write_varint` writes.
+pub fn read_varint_u128<R: Read>(reader: &mut R) -> io::Result<u128> {
+    let mut n: u128 = 0;
+    loop {
+        let byte = read_u8(reader)?;
+        if n > (u128::MAX >> 7) {
+            return Err(invalid_data("VARINT too large"));
+        }
+        n = (n << 7) | (byte & 0x7f) as u128;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        n = n
+            .checked_add(1)
+            .ok_or_else(|| invalid_data("VARINT too large"))?;
+    }
+}
+
+/// Inverse of `read_varint`, the encoding is unbounded so wider integers are written as well.
+pub fn write_varint<W: Write>(writer: &mut W, n: u128) -> io::Result<()> {
+    // 128 bits in 7 bits groups
+    let mut bytes = [0u8; 19];
+    let mut len = 0;
+    let mut n = n;
+    loop {
+        bytes[len] = (n & 0x7f) as u8 | if len > 0 { 0x80 } else { 0 };
+        if n <= 0x7f {
+            break;
+        }
+        n = (n >> 7) - 1;
+        len += 1;
+    }
+    bytes[..=len].reverse();
+    writer.write_all(&bytes[..=len])
+}
+
+/// `CompactSize` of the consensus encoding.
+pub fn read_compact_size<R: Read>(reader: &mut R) -> io::Result<u64> {
+    match read_u8(reader)? {
+        0xfd => Ok(u16::from_le_bytes(read_array(reader)?) as u64),
+        0xfe => Ok(u32::from_le_bytes(read_array(reader)?) as u64),
+        0xff => Ok(u64::from_le_bytes(read_array(reader)?)),
+        n => Ok(n as u64),
+    }
+}
+
+pub fn write_compact_size<W: Write>(writer: &mut W, n: u64) -> io::Result<()> {
+    match n {
+        0..=0xfc => writer.write_all(&[n as u8]),
+        0xfd..=0xffff => {
+            writer.write_all(&[0xfd])?;
+            writer.write_all(&(n as u16).to_le_bytes())
+        }
+        0x10000..=0xffff_ffff => {
+            writer.write_all(&[0xfe])?;
+            writer.write_all(&(n as u32).to_le_bytes())
+        }
+        _ => {
+            writer.write_all(&[0xff])?;
+            writer.write_all(&n.to_le_bytes())
+        }
+    }
+}
+
+/// Core's `CompressAmount`, amounts with trailing zeros are smaller, `n` must be at most `u64::MAX / 10`.
+pub fn compress_amount(n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    let mut n = n;
+    let mut e = 0;
+    while n % 10 == 0 && e < 9 {
+        n /= 10;
+        e += 1;
+    }
+    if e < 9 {
+        let d = n % 10;
+        n /= 10;
+        1 + (n * 9 + d - 1) * 10 + e
+    } else {
+        1 + (n - 1) * 10 + 9
+    }
+}
+
+/// Inverse of Core's `CompressAmount`, which favours amounts with trailing zeros.
+pub fn decompress_amount(x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    let mut x = x - 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        let d = (x % 9) + 1;
+        x /= 9;
+        x * 10 + d
+    } else {
+        x + 1
+    };
+    while e > 0 {
+        n *= 10;
+        e -= 1;
+    }
+    n
+}
+
+/// Read a script compressed with Core's `ScriptCompression`,
+/// standard P2PKH, P2SH and P2PK templates are stored without their opcodes.
+pub fn read_script<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let size = read_varint(reader)?;
+    match size {
+        0 => {
+            let hash: [u8; 20] = read_array(reader)?;
+            let mut script = vec![OP_DUP, OP_HASH160, 20];
+            script.extend_from_slice(&hash);
+            script.extend_from_slice(&[OP_EQUALVERIFY, OP_CHECKSIG]);
+            Ok(script)
+        }
+        1 => {
+            let hash: [u8; 20] = read_array(reader)?;
+            let mut script = vec![OP_HASH160, 20];
+            script.extend_from_slice(&hash);
+            script.push(OP_EQUAL);
+            Ok(script)
+        }
+        2 | 3 => {
+            let x: [u8; 32] = read_array(reader)?;
+            let mut script = vec![33, size as u8];
+            script.extend_from_slice(&x);
+            script.push(OP_CHECKSIG);
+            Ok(script)
+        }
+        4 | 5 => {
+            // Uncompressed public keys are stored compressed and must be decompressed
+            let x: [u8; 32] = read_array(reader)?;
+            let mut compressed = [0u8; 33];
+            compressed[0] = (size - 2) as u8;
+            compressed[1..].copy_from_slice(&x);
+            let pubkey = PublicKey::from_slice(&compressed)
+                .map_err(|_| invalid_data("Invalid P2PK public key"))?;
+            let mut script = vec![65];
+            script.extend_from_slice(&pubkey.serialize_uncompressed());
+            script.push(OP_CHECKSIG);
+            Ok(script)
+        }
+        size => {
+            let size = size - SPECIAL_SCRIPTS;
+            if size > MAX_SCRIPT_SIZE {
+                io::copy(&mut reader.take(size), &mut io::sink())?;
+                return Ok(vec![OP_RETURN]);
+            }
+            let mut script = vec![0u8; size as usize];
+            reader.read_exact(&mut script)?;
+            Ok(script)
+        }
+    }
+}
+
+/// Script templates of `compress_script_key` as (template, program offset, program size),
+/// the tag is the index. Adding a template changes the key encoding of the database.
+const SCRIPT_KEY_TEMPLATES: [(&[u8], usize, usize); 5] = [
+    // P2PKH, P2SH
+    (&[OP_DUP, OP_HASH160, 20], 3, 20),
+    (&[OP_HASH160, 20], 2, 20),
+    // P2WPKH, P2WSH, P2TR
+    (&[0x00, 20], 2, 20),
+    (&[0x00, 32], 2, 32),
+    (&[0x51, 32], 2, 32),
+];
+
+/// Key encoding of a script, in the spirit of Core's `ScriptCompression`:
+/// standard templates are a tag followed by their program, e.g. 21 bytes for P2PKH,
+/// other scripts are their size plus the number of templates, as a bincode varint, followed by the script.
+pub(crate) fn compress_script_key(script: &[u8]) -> Vec<u8> {
+    for (tag, (template, offset, size)) in SCRIPT_KEY_TEMPLATES.iter().enumerate() {
+        if is_template(script, template, *offset, *size) {
+            let mut key = Vec::with_capacity(1 + size);
+            key.push(tag as u8);
+            key.extend_from_slice(&script[*offset..offset + size]);
+            return key;
+        }
+    }
+
+    let mut key = Vec::with_capacity(9 + script.len());
+    let n = script.len() as u64 + SCRIPT_KEY_TEMPLATES.len() as u64;
+    match n {
+        0..=250 => key.push(n as u8),
+        251..=0xffff => {
+            key.push(251);
+            key.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        _ => {
+            key.push(252);
+            key.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+    }
+    key.extend_from_slice(script);
+    key
+}
+
+/// Number of bytes of the header of a script key starting with `tag`.
+pub(crate) fn script_key_header(tag: u8) -> usize {
+    match tag {
+        251 => 3,
+        252 => 5,
+        _ => 1,
+    }
+}
+
+/// Length of the script key at the start of `key`, which must hold at least its header.
+pub(crate) fn script_key_len(key: &[u8]) -> usize {
+    let header = script_key_header(key[0]);
+    let n = match key[0] {
+        251 => u16::from_be_bytes(key[1..3].try_into().unwrap()) as usize,
+        252 => u32::from_be_bytes(key[1..5].try_into().unwrap()) as usize,
+        n => n as usize,
+    };
+    match SCRIPT_KEY_TEMPLATES.get(n) {
+        Some((_, _, size)) => 1 + size,
+        None => header + n - SCRIPT_KEY_TEMPLATES.len(),
+    }
+}
+
+/// Inverse of `compress_script_key`, `key` is exactly one script key.
+pub(crate) fn decompress_script_key(key: &[u8]) -> Vec<u8> {
+    match SCRIPT_KEY_TEMPLATES.get(key[0] as usize) {
+        Some((template, offset, size)) => {
+            let mut script = Vec::with_capacity(offset + size + 2);
+            script.extend_from_slice(template);
+            script.extend_from_slice(&key[1..]);
+            match key[0] {
+                0 => script.extend_from_slice(&[OP_EQUALVERIFY, OP_CHECKSIG]),
+                1 => script.push(OP_EQUAL),
+                _ => {}
+            }
+            script
+        }
+        None => key[script_key_header(key[0])..].to_vec(),
+    }
+}
+
+fn is_template(script: &[u8], template: &[u8], offset: usize, size: usize) -> bool {
+    let suffix: &[u8] = match template {
+        [OP_DUP, ..] => &[OP_EQUALVERIFY, OP_CHECKSIG],
+        [OP_HASH160, ..] => &[OP_EQUAL],
+        _ => &[],
+    };
+    script.len() == offset + size + suffix.len()
+        && script.starts_with(template)
+        && script.ends_with(suffix)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    Ok(read_array::<R, 1>(reader)?[0])
+}
+
+pub(crate) fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint() {
+        let cases: [(&[u8], u64); 6] = [
+            (&[0x00], 0),
+            (&[0x7f], 127),
+            (&[0x80, 0x00], 128),
+            (&[0x80, 0x7f], 255),
+            (&[0xfe, 0x7f], 16383),
+            (&[0xff, 0x00], 16384),
+        ];
+        for (mut bytes, expected) in cases {
+            let mut written = Vec::new();
+            write_varint(&mut written, expected as u128).unwrap();
+            assert_eq!(written, bytes);
+            assert_eq!(read_varint(&mut bytes).unwrap(), expected);
+        }
+
+        let mut written = Vec::new();
+        write_varint(&mut written, u128::MAX).unwrap();
+        assert_eq!(written.len(), 19);
+    }
+
+    #[test]
+    fn script_key() {
+        let p2pkh = hex::decode("76a914000102030405060708090a0b0c0d0e0f1011121388ac").unwrap();
+        let p2tr = [&[0x51, 32][..], &[7; 32]].concat();
+        let raw = vec![OP_RETURN; 300];
+        for (script, len) in [(p2pkh, 21), (p2tr, 33), (raw, 303), (Vec::new(), 1)] {
+            let key = compress_script_key(&script);
+            assert_eq!(key.len(), len);
+            assert_eq!(script_key_len(&key), len);
+            assert_eq!(decompress_script_key(&key), script);
+        }
+        // A P2PKH template with a different suffix is a raw script
+        let key = compress_script_key(
+            &hex::decode("76a9140102030405060708090a0b0c0d0e0f101112131487").unwrap(),
+        );
+        assert_eq!(key[0], 24 + 5);
+    }
+
+    #[test]
+    fn amount() {
+        for n in [
+            0,
+            1,
+            50_000,
+            5_000_000_000,
+            2_100_000_000_000_000,
+            123_456_789,
+        ] {
+            assert_eq!(decompress_amount(compress_amount(n)), n);
+        }
+        assert_eq!(compress_amount(5_000_000_000), 50);
+        assert_eq!(decompress_amount(0), 0);
+        assert_eq!(decompress_amount(1), 1);
+        assert_eq!(decompress_amount(7), 1_000_000);
+        assert_eq!(decompress_amount(9), 100_000_000);
+        assert_eq!(decompress_amount(50), 5_000_000_000);
+        assert_eq!(decompress_amount(21_000_000), 2_100_000_000_000_000);
+    }
+
+    #[test]
+    fn script() {
+        let mut bytes: &[u8] = &[[0x00].as_slice(), &[0xab; 20]].concat();
+        let script = read_script(&mut bytes).unwrap();
+        assert_eq!(script.len(), 25);
+        assert_eq!(script[..3], [OP_DUP, OP_HASH160, 20]);
+
+        let mut bytes: &[u8] = &[[0x01].as_slice(), &[0xab; 20]].concat();
+        assert_eq!(read_script(&mut bytes).unwrap().len(), 23);
+
+        // Generator point of secp256k1, even y
+        let x = hex::decode("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+            .unwrap();
+        let mut bytes: &[u8] = &[[0x04].as_slice(), &x].concat();
+        let script = read_script(&mut bytes).unwrap();
+        assert_eq!(script.len(), 67);
+        assert_eq!(script[1], 0x04);
+        assert_eq!(script[2..34], x);
+
+        let mut bytes: &[u8] = &[[0x06 + 2].as_slice(), &[0x51, 0x52]].concat();
+        assert_eq!(read_script(&mut bytes).unwrap(), vec![0x51, 0x52]);
+    }
+}
@@ -1,15 +1,22 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::iter::Peekable;
 use std::marker::PhantomData;
 use std::ops::Range;
+use std::path::Path;
 
+use bigdecimal::BigDecimal;
+use rocksdb::checkpoint::Checkpoint;
 use rocksdb::{
-    ColumnFamilyDescriptor, DBIteratorWithThreadMode, Direction, IteratorMode, Options,
-    ReadOptions, SliceTransform, TransactionDB, TransactionDBOptions, WriteBatchWithTransaction,
+    Cache as BlockCache, ColumnFamilyDescriptor, DBIteratorWithThreadMode, Direction, IteratorMode,
+    Options, ReadOptions, SliceTransform, SnapshotWithThreadMode, WriteBatch, DB,
 };
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use crate::compress::script_key_len;
+use crate::muhash::MuHash;
 use crate::types::{U128Decimal, U256};
 
 mod bincode {
@@ -36,6 +43,188 @@ mod bincode {
     }
 }
 
+mod migrate;
+mod options;
+
+pub use options::{CfOptions, Compression, DbOptions};
+
+/// Version of the layout of the column families, recorded in `meta` and migrated on open,
+/// see `migrate::MIGRATIONS`.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Column families of the index by name, as tuned by `DbOptions::cfs`.
+pub const CF_NAMES: [&str; 5] = [
+    Block::CF_NAME,
+    BlockUndo::CF_NAME,
+    Utxo::CF_NAME,
+    UtxoKey::CF_NAME,
+    ScriptInfo::CF_NAME,
+];
+
+/// Column family of database wide values, by `VERSION` and `CHAIN` keys.
+const META_CF: &str = "meta";
+/// Column family entries are moved through while a migration rewrites them, empty otherwise.
+const MIGRATION_CF: &str = "migration";
+const VERSION: &str = "version";
+/// Genesis block hash of the indexed chain.
+const CHAIN: &str = "chain";
+
+/// Scripts in keys and values are encoded with `compress_script_key`,
+/// without a length prefix as the encoding has its own: `#[serde(with = "script")]`.
+mod script {
+    use std::fmt;
+
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    use crate::compress::{
+        compress_script_key, decompress_script_key, script_key_header, script_key_len,
+    };
+
+    pub fn serialize<S: Serializer>(script: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        super::serialize_raw(&compress_script_key(script), serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct ScriptVisitor;
+
+        impl<'de> Visitor<'de> for ScriptVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a script key")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+                let mut next = |key: &mut Vec<u8>| match seq.next_element::<u8>()? {
+                    Some(byte) => {
+                        key.push(byte);
+                        Ok(())
+                    }
+                    None => Err(de::Error::invalid_length(key.len(), &self)),
+                };
+                let mut key = Vec::new();
+                next(&mut key)?;
+                while key.len() < script_key_header(key[0]) {
+                    next(&mut key)?;
+                }
+                while key.len() < script_key_len(&key) {
+                    next(&mut key)?;
+                }
+                Ok(decompress_script_key(&key))
+            }
+        }
+
+        deserializer.deserialize_tuple(usize::MAX, ScriptVisitor)
+    }
+}
+
+/// Amounts at the chain's scale, satoshis for Bitcoin, are Core's `CompressAmount` as a `VARINT`
+/// shifted left by one. Any other amount is lossless as `VARINT(scale << 1 | 1)` then `VARINT(mantissa)`:
+/// `#[serde(with = "amount")]`.
+mod amount {
+    use std::fmt;
+
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    use crate::block::SATOSHI_SCALE;
+    use crate::compress::{compress_amount, decompress_amount, read_varint_u128, write_varint};
+    use crate::types::U128Decimal;
+
+    /// Amount in satoshis if it can be compressed.
+    fn satoshis(value: &U128Decimal) -> Option<u64> {
+        let U128Decimal(mantissa, scale) = *value;
+        let sats = if scale <= SATOSHI_SCALE {
+            mantissa.checked_mul(10u128.pow((SATOSHI_SCALE - scale) as u32))?
+        } else {
+            let divisor = 10u128.checked_pow((scale - SATOSHI_SCALE) as u32)?;
+            if mantissa % divisor != 0 {
+                return None;
+            }
+            mantissa / divisor
+        };
+        u64::try_from(sats)
+            .ok()
+            .filter(|sats| *sats <= u64::MAX / 10)
+    }
+
+    pub fn serialize<S: Serializer>(value: &U128Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut bytes = Vec::with_capacity(10);
+        match satoshis(value) {
+            Some(sats) => write_varint(&mut bytes, (compress_amount(sats) as u128) << 1),
+            None => write_varint(&mut bytes, (value.1 as u128) << 1 | 1)
+                .and_then(|_| write_varint(&mut bytes, value.0)),
+        }
+        .unwrap();
+        super::serialize_raw(&bytes, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<U128Decimal, D::Error> {
+        struct AmountVisitor;
+
+        impl<'de> Visitor<'de> for AmountVisitor {
+            type Value = U128Decimal;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an amount")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<U128Decimal, A::Error> {
+                let mut varint = || -> Result<u128, A::Error> {
+                    let mut bytes = Vec::new();
+                    loop {
+                        let byte: u8 = seq
+                            .next_element()?
+                            .ok_or_else(|| de::Error::invalid_length(bytes.len(), &self))?;
+                        bytes.push(byte);
+                        if byte & 0x80 == 0 {
+                            break;
+                        }
+                    }
+                    read_varint_u128(&mut bytes.as_slice()).map_err(de::Error::custom)
+                };
+
+                let head = varint()?;
+                if head & 1 == 0 {
+                    let compressed = u64::try_from(head >> 1).map_err(de::Error::custom)?;
+                    return Ok(U128Decimal(
+                        decompress_amount(compressed) as u128,
+                        SATOSHI_SCALE,
+                    ));
+                }
+                let scale = u8::try_from(head >> 1).map_err(de::Error::custom)?;
+                Ok(U128Decimal(varint()?, scale))
+            }
+        }
+
+        deserializer.deserialize_tuple(usize::MAX, AmountVisitor)
+    }
+}
+
+/// Bytes serialized as they are, as a tuple that bincode writes without a length prefix.
+fn serialize_raw<S: serde::Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    use serde::ser::SerializeTuple;
+
+    let mut tuple = serializer.serialize_tuple(bytes.len())?;
+    for byte in bytes {
+        tuple.serialize_element(byte)?;
+    }
+    tuple.end()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct Amount(#[serde(with = "amount")] U128Decimal);
+
+/// Script of a key, encoded as `script`.
+#[derive(Serialize)]
+struct ScriptRef<'a>(#[serde(with = "script")] &'a [u8]);
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ScriptKey(#[serde(with = "script")] Vec<u8>);
+
 trait CFStruct: Sized {
     type Key: Clone + Serialize + for<'de> Deserialize<'de>;
     type KeyRef<'a>: Serialize;
@@ -43,8 +232,13 @@ trait CFStruct: Sized {
 
     const CF_NAME: &'static str;
 
-    fn new_cf_descriptor() -> ColumnFamilyDescriptor {
-        ColumnFamilyDescriptor::new(Self::CF_NAME, Options::default())
+    /// Tuning for the access pattern of the column family, see `DbOptions::cfs` to override it.
+    fn cf_options() -> CfOptions {
+        CfOptions::default()
+    }
+
+    fn new_cf_descriptor(options: Options) -> ColumnFamilyDescriptor {
+        ColumnFamilyDescriptor::new(Self::CF_NAME, options)
     }
 
     fn key(&self) -> Cow<Self::Key>;
@@ -65,27 +259,19 @@ trait CFStruct: Sized {
         (key, value)
     }
 
-    fn batch_put(
-        rocksdb: &TransactionDB,
-        batch: &mut WriteBatchWithTransaction<true>,
-        data: &Self,
-    ) {
+    fn batch_put(rocksdb: &DB, batch: &mut WriteBatch, data: &Self) {
         let family = rocksdb.cf_handle(Self::CF_NAME).unwrap();
         let (key, value) = data.encode();
         batch.put_cf(family, &key, &value);
     }
 
-    fn batch_delete(
-        rocksdb: &TransactionDB,
-        batch: &mut WriteBatchWithTransaction<true>,
-        key: Self::KeyRef<'_>,
-    ) {
+    fn batch_delete(rocksdb: &DB, batch: &mut WriteBatch, key: Self::KeyRef<'_>) {
         let family = rocksdb.cf_handle(Self::CF_NAME).unwrap();
         let key = bincode::serialize(&key).unwrap();
         batch.delete_cf(family, &key);
     }
 
-    fn read(rocksdb: &TransactionDB, key: Self::KeyRef<'_>) -> Option<Self> {
+    fn read(rocksdb: &DB, key: Self::KeyRef<'_>) -> Option<Self> {
         let family = rocksdb.cf_handle(Self::CF_NAME).unwrap();
         let key = bincode::serialize(&key).unwrap();
         rocksdb
@@ -95,7 +281,7 @@ trait CFStruct: Sized {
     }
 
     fn iterator<'a>(
-        rocksdb: &'a TransactionDB,
+        rocksdb: &'a DB,
         readopts: ReadOptions,
         mode: IteratorMode,
     ) -> CFIterator<'a, Self> {
@@ -109,7 +295,7 @@ trait CFStruct: Sized {
 }
 
 pub struct CFIterator<'a, D> {
-    inner: DBIteratorWithThreadMode<'a, TransactionDB>,
+    inner: DBIteratorWithThreadMode<'a, DB>,
     phantom: PhantomData<D>,
 }
 
@@ -128,12 +314,14 @@ pub struct Block {
     pub height: u64,
     pub hash: U256,
     pub prev_hash: U256,
+    /// Hash of the UTXO set once the block is connected.
+    pub muhash: MuHash,
 }
 
 impl CFStruct for Block {
     type Key = u64;
     type KeyRef<'a> = &'a u64;
-    type Value = (U256, U256);
+    type Value = (U256, U256, MuHash);
 
     const CF_NAME: &'static str = "block";
 
@@ -142,14 +330,15 @@ impl CFStruct for Block {
     }
 
     fn value(&self) -> Self::Value {
-        (self.hash, self.prev_hash)
+        (self.hash, self.prev_hash, self.muhash.clone())
     }
 
-    fn assemble(height: Self::Key, (hash, prev_hash): Self::Value) -> Self {
+    fn assemble(height: Self::Key, (hash, prev_hash, muhash): Self::Value) -> Self {
         Self {
             height,
             hash,
             prev_hash,
+            muhash,
         }
     }
 }
@@ -169,6 +358,21 @@ enum Undo {
     ScriptInfoDelete(<ScriptInfo as CFStruct>::Key),
 }
 
+impl Undo {
+    /// Estimated in-memory size for `Cache`.
+    fn size(&self) -> usize {
+        ENTRY_SIZE
+            + match self {
+                Undo::UtxoPut(utxo) => utxo.key.script.len(),
+                Undo::UtxoDelete(key) => key.script.len(),
+                Undo::UtxoKeyPut(key) => key.script.len(),
+                Undo::UtxoKeyDelete(_) => 0,
+                Undo::ScriptInfoPut(info) => info.script.len(),
+                Undo::ScriptInfoDelete(script) => script.0.len(),
+            }
+    }
+}
+
 impl CFStruct for BlockUndo {
     type Key = u64;
     type KeyRef<'a> = &'a u64;
@@ -176,6 +380,14 @@ impl CFStruct for BlockUndo {
 
     const CF_NAME: &'static str = "block_undo";
 
+    /// Written once per block, only read back on a reorg.
+    fn cf_options() -> CfOptions {
+        CfOptions {
+            compression: Some(vec![Compression::Zstd]),
+            ..Default::default()
+        }
+    }
+
     fn key(&self) -> Cow<Self::Key> {
         Cow::Borrowed(&self.height)
     }
@@ -191,7 +403,7 @@ impl CFStruct for BlockUndo {
 
 /// Vout represents a transaction output in a transaction.
 /// Where `n` is the index of the output in the transaction.
-#[derive(Serialize, Deserialize, Copy, Clone, Eq, Hash, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, Hash, PartialEq)]
 pub struct Vout {
     pub txid: U256,
     pub n: u32,
@@ -209,34 +421,31 @@ impl fmt::Display for Vout {
 pub struct Utxo {
     pub key: UtxoKey,
     pub coinbase: bool,
+    #[serde(with = "amount")]
     pub value: U128Decimal,
 }
 
 impl CFStruct for Utxo {
     type Key = UtxoKey;
     type KeyRef<'a> = &'a UtxoKey;
-    type Value = (bool, U128Decimal);
+    type Value = (bool, Amount);
 
     const CF_NAME: &'static str = "utxo";
 
-    fn new_cf_descriptor() -> ColumnFamilyDescriptor {
-        let mut options = Options::default();
+    /// Iterated by script, the bloom filter is of the script prefixes, skipping scripts without UTXOs.
+    fn cf_options() -> CfOptions {
+        CfOptions {
+            bloom_bits: Some(10.0),
+            write_buffer_size: Some(128 << 20),
+            ..Default::default()
+        }
+    }
+
+    fn new_cf_descriptor(mut options: Options) -> ColumnFamilyDescriptor {
         options.set_prefix_extractor(SliceTransform::create(
-            "ScriptPrefix",
-            |key| {
-                const SINGLE_BYTE_MAX: u8 = 250;
-                const U16_BYTE: u8 = 251;
-
-                match key[0] {
-                    byte @ 0..=SINGLE_BYTE_MAX => &key[..(byte + 1) as usize],
-                    U16_BYTE => {
-                        &key[..(3 + u16::from_be_bytes(key[1..3].try_into().unwrap()) as usize)]
-                    }
-                    _ => {
-                        panic!("Byte type not supported")
-                    }
-                }
-            },
+            // Renamed with the key encoding, filters built by the former transform aren't used
+            "ScriptKeyPrefix",
+            |key| &key[..script_key_len(key).min(key.len())],
             None,
         ));
 
@@ -248,11 +457,11 @@ impl CFStruct for Utxo {
     }
 
     fn value(&self) -> Self::Value {
-        (self.coinbase, self.value)
+        (self.coinbase, Amount(self.value))
     }
 
     fn assemble(key: Self::Key, value: Self::Value) -> Self {
-        let (coinbase, value) = value;
+        let (coinbase, Amount(value)) = value;
         Self {
             key,
             coinbase,
@@ -265,6 +474,7 @@ impl CFStruct for Utxo {
 /// Key: (txid, n) -> Value: (script, height)
 #[derive(Serialize, Deserialize, Clone)]
 pub struct UtxoKey {
+    #[serde(with = "script")]
     pub script: Vec<u8>,
     pub height: u64,
     pub vout: Vout,
@@ -273,20 +483,29 @@ pub struct UtxoKey {
 impl CFStruct for UtxoKey {
     type Key = Vout;
     type KeyRef<'a> = &'a Vout;
-    type Value = (u64, Vec<u8>);
+    type Value = (u64, ScriptKey);
 
     const CF_NAME: &'static str = "utxo_key";
 
+    /// Looked up for every spent output, mostly of recent blocks.
+    fn cf_options() -> CfOptions {
+        CfOptions {
+            bloom_bits: Some(10.0),
+            write_buffer_size: Some(128 << 20),
+            ..Default::default()
+        }
+    }
+
     fn key(&self) -> Cow<Self::Key> {
         Cow::Borrowed(&self.vout)
     }
 
     fn value(&self) -> Self::Value {
-        (self.height, self.script.clone())
+        (self.height, ScriptKey(self.script.clone()))
     }
 
     fn assemble(vout: Self::Key, value: Self::Value) -> Self {
-        let (height, script) = value;
+        let (height, ScriptKey(script)) = value;
         Self {
             vout,
             script,
@@ -297,9 +516,13 @@ impl CFStruct for UtxoKey {
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ScriptInfo {
+    #[serde(with = "script")]
     pub script: Vec<u8>,
+    #[serde(with = "amount")]
     pub balance: U128Decimal,
+    #[serde(with = "amount")]
     pub total_sent: U128Decimal,
+    #[serde(with = "amount")]
     pub total_received: U128Decimal,
     pub tx_count: u64,
 }
@@ -329,29 +552,37 @@ impl ScriptInfo {
 }
 
 impl CFStruct for ScriptInfo {
-    type Key = Vec<u8>;
-    type KeyRef<'a> = &'a [u8];
-    type Value = (U128Decimal, U128Decimal, U128Decimal, u64);
+    type Key = ScriptKey;
+    type KeyRef<'a> = ScriptRef<'a>;
+    type Value = (Amount, Amount, Amount, u64);
 
     const CF_NAME: &'static str = "script_info";
 
+    /// Looked up for every output and spent output, and by `getaddressinfo`.
+    fn cf_options() -> CfOptions {
+        CfOptions {
+            bloom_bits: Some(10.0),
+            ..Default::default()
+        }
+    }
+
     fn key(&self) -> Cow<Self::Key> {
-        Cow::Borrowed(&self.script)
+        Cow::Owned(ScriptKey(self.script.clone()))
     }
 
     fn value(&self) -> Self::Value {
         (
-            self.balance,
-            self.total_sent,
-            self.total_received,
+            Amount(self.balance),
+            Amount(self.total_sent),
+            Amount(self.total_received),
             self.tx_count,
         )
     }
 
     fn assemble(key: Self::Key, value: Self::Value) -> Self {
-        let (balance, total_sent, total_received, tx_count) = value;
+        let (Amount(balance), Amount(total_sent), Amount(total_received), tx_count) = value;
         Self {
-            script: key,
+            script: key.0,
             balance,
             total_sent,
             total_received,
@@ -360,52 +591,483 @@ impl CFStruct for ScriptInfo {
     }
 }
 
+#[derive(Debug)]
+pub enum Error {
+    /// There is no block to pop.
+    Empty,
+    /// The undo data of the block at `height` was pruned,
+    /// the fork is deeper than the retained window and can't be disconnected.
+    UndoNotFound { height: u64 },
+    /// The undo data of the block at `height` doesn't match the UTXO set at `vout`,
+    /// nothing was disconnected.
+    UndoMismatch { height: u64, vout: Vout },
+}
+
+/// Abort the process at `name` when `OXTU_FAILPOINT` is set to it, for fault-injection tests.
+#[cfg(test)]
+fn failpoint(name: &str) {
+    if std::env::var("OXTU_FAILPOINT").as_deref() == Ok(name) {
+        std::process::abort();
+    }
+}
+
+#[cfg(not(test))]
+fn failpoint(_name: &str) {}
+
+/// Consistent point-in-time view of `Db`, unaffected by blocks connected or popped meanwhile.
+pub struct DbSnapshot<'a> {
+    rocksdb: &'a DB,
+    snapshot: SnapshotWithThreadMode<'a, DB>,
+}
+
+impl DbSnapshot<'_> {
+    fn iterator<D: CFStruct>(&self, readopts: ReadOptions, mode: IteratorMode) -> CFIterator<D> {
+        let family = self.rocksdb.cf_handle(D::CF_NAME).unwrap();
+        let iter = self.snapshot.iterator_cf_opt(family, readopts, mode);
+        CFIterator::<D> {
+            inner: iter,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn peek(&self) -> Option<Block> {
+        self.iterator::<Block>(ReadOptions::default(), IteratorMode::End)
+            .next()
+    }
+
+    pub fn iterator_utxo(&self) -> CFIterator<Utxo> {
+        self.iterator(ReadOptions::default(), IteratorMode::Start)
+    }
+
+    pub fn iterator_script_info(&self) -> CFIterator<ScriptInfo> {
+        self.iterator(ReadOptions::default(), IteratorMode::Start)
+    }
+
+    /// See `Db::find_utxo`.
+    pub fn find_utxo(&self, vout: &Vout) -> Option<Utxo> {
+        let vout_key = self.read::<UtxoKey>(vout)?;
+        self.read(&vout_key)
+    }
+
+    pub fn get_script_info(&self, script: &[u8]) -> Option<ScriptInfo> {
+        self.read(ScriptRef(script))
+    }
+
+    /// See `Db::iterator_script_utxo`.
+    pub fn iterator_script_utxo(
+        &self,
+        script: &[u8],
+        upper_lower_bound: Range<Option<u64>>,
+    ) -> CFIterator<Utxo> {
+        let (opts, prefix) = script_utxo_opts(script, upper_lower_bound);
+        self.iterator(opts, IteratorMode::From(&prefix, Direction::Forward))
+    }
+
+    fn read<D: CFStruct>(&self, key: D::KeyRef<'_>) -> Option<D> {
+        let family = self.rocksdb.cf_handle(D::CF_NAME).unwrap();
+        let key = bincode::serialize(&key).unwrap();
+        self.snapshot
+            .get_cf(family, &key)
+            .unwrap()
+            .map(|value| D::decode((&key, &value)))
+    }
+
+    /// Walk `utxo`, `utxo_key`, `script_info` and `block` reporting every broken invariant.
+    pub fn verify(&self, mut report: impl FnMut(Violation)) -> Verified {
+        let mut verified = Verified::default();
+        let mut violations = 0;
+        let mut report = |violation| {
+            violations += 1;
+            report(violation)
+        };
+
+        for key in self.iterator::<UtxoKey>(ReadOptions::default(), IteratorMode::Start) {
+            if self.read::<Utxo>(&key).is_none() {
+                report(Violation::MissingUtxo(key.vout));
+            }
+        }
+
+        // Both are ordered by script, balances are compared as each script's UTXOs are summed
+        let mut infos = self
+            .iterator::<ScriptInfo>(ReadOptions::default(), IteratorMode::Start)
+            .peekable();
+        let mut group: Option<(Vec<u8>, U128Decimal)> = None;
+        for utxo in self.iterator::<Utxo>(ReadOptions::default(), IteratorMode::Start) {
+            verified.utxos += 1;
+            match self.read::<UtxoKey>(&utxo.key.vout) {
+                Some(key) if key.script == utxo.key.script && key.height == utxo.key.height => {}
+                _ => report(Violation::MissingUtxoKey(utxo.key.vout)),
+            }
+
+            match &mut group {
+                Some((script, sum)) if *script == utxo.key.script => *sum += utxo.value,
+                _ => {
+                    if let Some(group) = group.take() {
+                        verified.scripts += 1;
+                        verify_balance(&mut infos, Some(group), &mut report);
+                    }
+                    group = Some((utxo.key.script, utxo.value));
+                }
+            }
+        }
+        if group.is_some() {
+            verified.scripts += 1;
+        }
+        verify_balance(&mut infos, group, &mut report);
+        verify_balance(&mut infos, None, &mut report);
+
+        let mut prev = None;
+        for block in self.iterator::<Block>(ReadOptions::default(), IteratorMode::Start) {
+            verified.blocks += 1;
+            // Blocks are pruned with their undo data, but the base of an imported snapshot
+            // has none, it is the lowest block without its parent
+            let boundary = block.height > 0 && prev != Some(block.height - 1);
+            if !boundary && self.read::<BlockUndo>(&block.height).is_none() {
+                report(Violation::MissingBlockUndo {
+                    height: block.height,
+                });
+            }
+            prev = Some(block.height);
+        }
+
+        verified.violations = violations;
+        verified
+    }
+}
+
+/// Compare the `ScriptInfo` balance of `group` with the sum of its UTXOs,
+/// `ScriptInfo` ordered before it have no UTXOs and must have no balance.
+/// `None` checks all the remaining `ScriptInfo`.
+fn verify_balance(
+    infos: &mut Peekable<CFIterator<ScriptInfo>>,
+    group: Option<(Vec<u8>, U128Decimal)>,
+    report: &mut impl FnMut(Violation),
+) {
+    let key = group
+        .as_ref()
+        .map(|(script, _)| bincode::serialize(&ScriptRef(script)).unwrap());
+    let before = |info: &ScriptInfo| match &key {
+        Some(key) => bincode::serialize(&ScriptRef(&info.script)).unwrap() < *key,
+        None => true,
+    };
+    while let Some(info) = infos.next_if(before) {
+        if !info.balance.is_zero() {
+            report(Violation::BalanceMismatch {
+                script: info.script,
+                balance: info.balance,
+                utxos: U128Decimal::zero(),
+            });
+        }
+    }
+
+    if let Some((script, utxos)) = group {
+        let balance = infos
+            .next_if(|info| info.script == script)
+            .map(|info| info.balance)
+            .unwrap_or_else(U128Decimal::zero);
+        if BigDecimal::from(balance) != BigDecimal::from(utxos) {
+            report(Violation::BalanceMismatch {
+                script,
+                balance,
+                utxos,
+            });
+        }
+    }
+}
+
+/// Invariant of the column families that doesn't hold, see `DbSnapshot::verify`.
+#[derive(Debug)]
+pub enum Violation {
+    /// `UtxoKey` without the `Utxo` it points to.
+    MissingUtxo(Vout),
+    /// `Utxo` without a `UtxoKey`, or with one pointing to another script or height.
+    MissingUtxoKey(Vout),
+    /// `ScriptInfo` balance isn't the sum of the script's UTXOs.
+    BalanceMismatch {
+        script: Vec<u8>,
+        balance: U128Decimal,
+        utxos: U128Decimal,
+    },
+    /// Retained `Block` without its `BlockUndo`, it can't be popped.
+    MissingBlockUndo { height: u64 },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::MissingUtxo(vout) => write!(f, "Utxo not found: {}", vout),
+            Violation::MissingUtxoKey(vout) => write!(f, "UtxoKey not found: {}", vout),
+            Violation::BalanceMismatch {
+                script,
+                balance,
+                utxos,
+            } => write!(
+                f,
+                "ScriptInfo balance mismatch: {}, balance: {}, utxos: {}",
+                hex::encode(script),
+                BigDecimal::from(*balance),
+                BigDecimal::from(*utxos)
+            ),
+            Violation::MissingBlockUndo { height } => {
+                write!(f, "BlockUndo not found: {}", height)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Verified {
+    pub utxos: u64,
+    pub scripts: u64,
+    pub blocks: u64,
+    pub violations: u64,
+}
+
+/// Rough in-memory size of a cache entry, excluding its script.
+const ENTRY_SIZE: usize = 128;
+
+/// Write-back cache of the UTXO set in front of `Db`, as bitcoind's `-dbcache`.
+/// Outputs created and spent before a flush are never written, only their undo data is.
+pub struct Cache {
+    /// Estimated size in bytes above which the cache is full.
+    capacity: usize,
+    size: usize,
+    coins: HashMap<Vout, Coin>,
+    infos: HashMap<Vec<u8>, ScriptInfo>,
+    blocks: Vec<(Block, BlockUndo)>,
+}
+
+enum Coin {
+    /// Created since the last flush, not in the database yet.
+    Fresh(Utxo),
+    /// Spent since the last flush, deleted from the database on flush.
+    Spent(UtxoKey),
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            coins: HashMap::new(),
+            infos: HashMap::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.size >= self.capacity
+    }
+
+    /// Blocks connected since the last flush.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
 #[must_use]
 pub struct Db {
-    rocksdb: TransactionDB,
+    rocksdb: DB,
 }
 
 impl Db {
+    fn cf_descriptors(options: &DbOptions) -> Vec<ColumnFamilyDescriptor> {
+        fn descriptor<D: CFStruct>(
+            options: &DbOptions,
+            cache: &BlockCache,
+        ) -> ColumnFamilyDescriptor {
+            D::new_cf_descriptor(options.cf_options(D::CF_NAME, D::cf_options(), cache))
+        }
+
+        let cache = BlockCache::new_lru_cache(options.block_cache_size);
+        vec![
+            descriptor::<Block>(options, &cache),
+            descriptor::<BlockUndo>(options, &cache),
+            descriptor::<Utxo>(options, &cache),
+            descriptor::<UtxoKey>(options, &cache),
+            descriptor::<ScriptInfo>(options, &cache),
+            ColumnFamilyDescriptor::new(META_CF, Options::default()),
+            ColumnFamilyDescriptor::new(MIGRATION_CF, Options::default()),
+        ]
+    }
+
     pub fn open(path: &str) -> Self {
+        Self::with_options(path, &DbOptions::default())
+    }
+
+    pub fn with_options(path: &str, db_options: &DbOptions) -> Self {
         let mut options = Options::default();
         options.create_if_missing(true);
         options.create_missing_column_families(true);
+        options.set_max_open_files(db_options.max_open_files);
+
+        let cfs = Self::cf_descriptors(db_options);
+        let rocksdb =
+            DB::open_cf_descriptors(&options, path, cfs).expect("Failed to open database");
+
+        let db = Self { rocksdb };
+        db.migrate();
+        db
+    }
+
+    /// Open the database at `path`, written by another process, as a read-only secondary instance
+    /// keeping its own logs in `secondary_path`. It sees new writes after `try_catch_up`.
+    /// Panics unless the database was migrated to `SCHEMA_VERSION` by the primary instance.
+    pub fn open_secondary(path: &str, secondary_path: &str, db_options: &DbOptions) -> Self {
+        let mut options = Options::default();
+        // Required by secondary instances, files deleted by the primary instance must stay readable
+        options.set_max_open_files(-1);
+        let rocksdb = DB::open_cf_descriptors_as_secondary(
+            &options,
+            path,
+            secondary_path,
+            Self::cf_descriptors(db_options),
+        )
+        .expect("Failed to open database as secondary");
+
+        let db = Self { rocksdb };
+        let version = db.read_meta::<u32>(VERSION).unwrap_or(0);
+        if version != SCHEMA_VERSION {
+            panic!(
+                "Schema version {} of the primary instance, {} is expected",
+                version, SCHEMA_VERSION
+            );
+        }
+        db
+    }
+
+    /// Catch up with the writes of the primary instance, see `open_secondary`.
+    pub fn try_catch_up(&self) -> Result<(), rocksdb::Error> {
+        self.rocksdb.try_catch_up_with_primary()
+    }
+
+    /// Bring the column families to `SCHEMA_VERSION`, panics on a version this build doesn't know.
+    fn migrate(&self) {
+        let version = match self.read_meta::<u32>(VERSION) {
+            Some(version) => version,
+            None if self.is_empty() => {
+                let mut batch = WriteBatch::default();
+                self.batch_put_meta(&mut batch, VERSION, &SCHEMA_VERSION);
+                self.rocksdb.write(batch).expect("Failed to write version");
+                SCHEMA_VERSION
+            }
+            // Written before the version was recorded
+            None => 0,
+        };
+        if version > SCHEMA_VERSION {
+            panic!(
+                "Unknown schema version: {}, up to {} is supported",
+                version, SCHEMA_VERSION
+            );
+        }
+
+        if version < SCHEMA_VERSION {
+            tracing::info!("Migrating schema: {} -> {}", version, SCHEMA_VERSION);
+            migrate::MIGRATIONS[version as usize](self);
+        }
+    }
 
-        let tx_options = TransactionDBOptions::default();
+    fn is_empty(&self) -> bool {
+        [Block::CF_NAME, Utxo::CF_NAME, ScriptInfo::CF_NAME]
+            .iter()
+            .all(|name| {
+                let family = self.rocksdb.cf_handle(name).unwrap();
+                self.rocksdb
+                    .iterator_cf_opt(family, ReadOptions::default(), IteratorMode::Start)
+                    .next()
+                    .is_none()
+            })
+    }
+
+    fn read_meta<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let family = self.rocksdb.cf_handle(META_CF).unwrap();
+        self.rocksdb
+            .get_pinned_cf(family, key)
+            .unwrap()
+            .map(|value| bincode::deserialize(&value).unwrap())
+    }
+
+    fn batch_put_meta<T: Serialize>(&self, batch: &mut WriteBatch, key: &str, value: &T) {
+        let family = self.rocksdb.cf_handle(META_CF).unwrap();
+        batch.put_cf(family, key, bincode::serialize(value).unwrap());
+    }
 
-        let cfs = vec![
-            Block::new_cf_descriptor(),
-            BlockUndo::new_cf_descriptor(),
-            Utxo::new_cf_descriptor(),
-            UtxoKey::new_cf_descriptor(),
-            ScriptInfo::new_cf_descriptor(),
-        ];
+    pub fn schema_version(&self) -> u32 {
+        self.read_meta(VERSION).expect("Schema version not found")
+    }
 
-        let rocksdb = TransactionDB::open_cf_descriptors(&options, &tx_options, path, cfs)
-            .expect("Failed to open database");
+    /// RocksDB checkpoint of the database in `path`, which must not exist: SST files are hard linked
+    /// when on the same filesystem, copied otherwise.
+    pub fn checkpoint(&self, path: &Path) -> Result<(), rocksdb::Error> {
+        Checkpoint::new(&self.rocksdb)?.create_checkpoint(path)
+    }
+
+    /// Genesis block hash of the chain the database was indexed from, once recorded by `set_chain`.
+    pub fn chain(&self) -> Option<U256> {
+        self.read_meta(CHAIN)
+    }
 
-        Self { rocksdb }
+    pub fn set_chain(&self, genesis: U256) {
+        let mut batch = WriteBatch::default();
+        self.batch_put_meta(&mut batch, CHAIN, &genesis);
+        self.rocksdb.write(batch).expect("Failed to write chain")
     }
 
     pub fn peek(&self) -> Option<Block> {
         Block::iterator(&self.rocksdb, ReadOptions::default(), IteratorMode::End).next()
     }
 
-    pub fn pop(&self) -> Block {
-        let block = self.peek().expect("Failed to pop");
+    pub fn snapshot(&self) -> DbSnapshot<'_> {
+        DbSnapshot {
+            rocksdb: &self.rocksdb,
+            snapshot: self.rocksdb.snapshot(),
+        }
+    }
 
-        let mut batch = WriteBatchWithTransaction::default();
-        Block::batch_delete(&self.rocksdb, &mut batch, &block.key());
+    /// Disconnect the tip, applying its undo data and moving the tip to its parent in one batch.
+    /// The undo data is verified against the UTXO set before anything is written,
+    /// a crash leaves the database either at the tip or at its parent.
+    pub fn pop(&self) -> Result<Block, Error> {
+        let block = self.peek().ok_or(Error::Empty)?;
+        let block_undo =
+            BlockUndo::read(&self.rocksdb, &block.height).ok_or(Error::UndoNotFound {
+                height: block.height,
+            })?;
+        // The parent becomes the tip, it carries the MuHash the next block is connected on
+        if block.height > 0 && Block::read(&self.rocksdb, &(block.height - 1)).is_none() {
+            return Err(Error::UndoNotFound {
+                height: block.height - 1,
+            });
+        }
 
-        let block_undo = BlockUndo::read(&self.rocksdb, &block.height).unwrap();
+        let mut batch = WriteBatch::default();
+        Block::batch_delete(&self.rocksdb, &mut batch, &block.key());
         BlockUndo::batch_delete(&self.rocksdb, &mut batch, &block_undo.key());
 
         for undo in block_undo.vec.iter() {
             match undo {
                 Undo::UtxoPut(utxo) => {
+                    // Spent by the block, it can't be unspent
+                    if UtxoKey::read(&self.rocksdb, &utxo.key.vout).is_some() {
+                        return Err(Error::UndoMismatch {
+                            height: block.height,
+                            vout: utxo.key.vout,
+                        });
+                    }
                     Utxo::batch_put(&self.rocksdb, &mut batch, utxo);
                 }
                 Undo::UtxoDelete(key) => {
+                    // Created by the block, it must still be unspent as every child was popped
+                    if Utxo::read(&self.rocksdb, key).is_none() {
+                        return Err(Error::UndoMismatch {
+                            height: block.height,
+                            vout: key.vout,
+                        });
+                    }
                     Utxo::batch_delete(&self.rocksdb, &mut batch, key);
                 }
                 Undo::UtxoKeyPut(vout_script) => {
@@ -418,71 +1080,93 @@ impl Db {
                     ScriptInfo::batch_put(&self.rocksdb, &mut batch, info);
                 }
                 Undo::ScriptInfoDelete(key) => {
-                    ScriptInfo::batch_delete(&self.rocksdb, &mut batch, key);
+                    ScriptInfo::batch_delete(&self.rocksdb, &mut batch, ScriptRef(&key.0));
                 }
             }
         }
 
-        block
+        failpoint("pop::before_write");
+        self.rocksdb.write(batch).expect("Failed to pop block");
+        failpoint("pop::after_write");
+        Ok(block)
     }
 
-    pub fn push(&self, rpc_block: crate::rpc::Block) {
-        let mut batch = WriteBatchWithTransaction::default();
-        let height: u64 = rpc_block.height;
+    /// Connect `block` and flush it right away, see `connect`.
+    pub fn push(&self, block: crate::block::Block) {
+        let mut cache = Cache::new(0);
+        self.connect(&mut cache, block);
+        self.flush(&mut cache);
+    }
+
+    /// Connect `block` on top of `cache`, nothing is written until `flush`.
+    /// The undo data is the same as connecting block by block, the blocks can be popped once flushed.
+    pub fn connect(&self, cache: &mut Cache, block: crate::block::Block) {
+        let height: u64 = block.height;
 
         let mut undos = Vec::<Undo>::new();
         let mut utxos = HashMap::<Vout, Utxo>::new();
-        let mut infos = HashMap::<Vec<u8>, ScriptInfo>::new();
+        let mut muhash = match cache.blocks.last() {
+            Some((tip, _)) => tip.muhash.clone(),
+            None => self.peek().map(|tip| tip.muhash).unwrap_or_default(),
+        };
+        let mut touched = HashSet::<Vec<u8>>::new();
+        let Cache {
+            coins, infos, size, ..
+        } = cache;
 
         let mut update_info =
-            |undos: &mut Vec<Undo>, script: &[u8], f: &dyn Fn(&mut ScriptInfo)| match infos
-                .get_mut(script)
-            {
-                Some(info) => f(info),
-                None => {
-                    let mut info = match ScriptInfo::read(&self.rocksdb, script) {
+            |undos: &mut Vec<Undo>, script: &[u8], f: &dyn Fn(&mut ScriptInfo)| {
+                // The first change of a script in this block records its prior state
+                if touched.insert(script.to_vec()) {
+                    let info = match infos.get(script) {
+                        Some(info) => Some(info.clone()),
+                        None => ScriptInfo::read(&self.rocksdb, ScriptRef(script)),
+                    };
+                    match info {
                         None => {
                             let info = ScriptInfo::new(script);
                             undos.push(Undo::ScriptInfoDelete(info.key().into_owned()));
-                            info
+                            infos.insert(script.to_vec(), info);
                         }
                         Some(info) => {
                             undos.push(Undo::ScriptInfoPut(info.clone()));
-                            info
+                            infos.entry(script.to_vec()).or_insert(info);
                         }
-                    };
-                    f(&mut info);
-                    infos.insert(script.to_vec(), info);
+                    }
                 }
+                f(infos.get_mut(script).unwrap());
             };
 
-        for tx in rpc_block.tx {
-            let txid = U256::from_hex(&tx.txid);
+        for tx in block.tx {
+            let txid = tx.txid;
             let mut coinbase = false;
 
             for tx_vin in tx.vin {
-                match tx_vin.txid.as_ref() {
-                    Some(txid) => {
-                        let vout = Vout {
-                            txid: U256::from_hex(txid),
-                            n: tx_vin.vout.expect("Vout is missing when txid is present"),
-                        };
-                        match utxos.remove(&vout) {
-                            None => {
-                                let utxo = self.get_utxo(&vout);
-                                update_info(&mut undos, &utxo.key.script, &|info| {
-                                    info.add_spent(utxo.value);
-                                });
-                                undos.push(Undo::UtxoKeyPut(utxo.key().into_owned()));
-                                undos.push(Undo::UtxoPut(utxo));
-                            }
-                            Some(utxo) => {
-                                update_info(&mut undos, &utxo.key.script, &|info| {
-                                    info.add_spent(utxo.value);
-                                });
-                            }
+                match tx_vin.prevout {
+                    Some(vout) => match utxos.remove(&vout) {
+                        None => {
+                            let utxo = match coins.remove(&vout) {
+                                Some(Coin::Fresh(utxo)) => utxo,
+                                Some(Coin::Spent(_)) => panic!("Utxo already spent {}", vout),
+                                None => {
+                                    let utxo = self.get_utxo(&vout);
+                                    coins.insert(vout, Coin::Spent(utxo.key.clone()));
+                                    utxo
+                                }
+                            };
+                            muhash.remove_utxo(&utxo);
+                            update_info(&mut undos, &utxo.key.script, &|info| {
+                                info.add_spent(utxo.value);
+                            });
+                            undos.push(Undo::UtxoKeyPut(utxo.key().into_owned()));
+                            undos.push(Undo::UtxoPut(utxo));
                         }
-                    }
+                        Some(utxo) => {
+                            update_info(&mut undos, &utxo.key.script, &|info| {
+                                info.add_spent(utxo.value);
+                            });
+                        }
+                    },
                     None => {
                         coinbase = true;
                     }
@@ -492,12 +1176,12 @@ impl Db {
             for tx_vout in tx.vout {
                 let utxo = Utxo {
                     key: UtxoKey {
-                        script: hex::decode(&tx_vout.script_pub_key.hex).unwrap(),
+                        script: tx_vout.script,
                         vout: Vout { txid, n: tx_vout.n },
                         height,
                     },
                     coinbase,
-                    value: tx_vout.value.into(),
+                    value: tx_vout.value,
                 };
 
                 update_info(&mut undos, &utxo.key.script, &|info| {
@@ -508,64 +1192,144 @@ impl Db {
             }
         }
 
-        for (_, utxo) in utxos {
-            Utxo::batch_put(&self.rocksdb, &mut batch, &utxo);
+        for (vout, utxo) in utxos {
+            muhash.insert_utxo(&utxo);
             undos.push(Undo::UtxoDelete(utxo.key().into_owned()));
-
-            let utxo_key = utxo.key;
-            UtxoKey::batch_put(&self.rocksdb, &mut batch, &utxo_key);
-            undos.push(Undo::UtxoKeyDelete(utxo_key.key().into_owned()));
+            undos.push(Undo::UtxoKeyDelete(vout));
+            coins.insert(vout, Coin::Fresh(utxo));
         }
 
-        for (_, info) in infos {
-            ScriptInfo::batch_put(&self.rocksdb, &mut batch, &info);
+        // Every undo entry has its counterpart in the coins or script infos
+        *size += 2 * undos.iter().map(Undo::size).sum::<usize>();
+
+        let block = Block {
+            height,
+            hash: block.hash,
+            prev_hash: block.prev_hash.unwrap_or_else(U256::zero),
+            muhash,
+        };
+        let block_undo = BlockUndo { height, vec: undos };
+        cache.blocks.push((block, block_undo));
+    }
+
+    /// Write every block connected on `cache` in one batch and empty it.
+    pub fn flush(&self, cache: &mut Cache) {
+        if cache.blocks.is_empty() {
+            return;
         }
 
-        for undo in undos.iter() {
-            match undo {
-                Undo::UtxoPut(utxo) => {
-                    Utxo::batch_delete(&self.rocksdb, &mut batch, &utxo.key());
+        let mut batch = WriteBatch::default();
+        for (vout, coin) in cache.coins.drain() {
+            match coin {
+                Coin::Fresh(utxo) => {
+                    Utxo::batch_put(&self.rocksdb, &mut batch, &utxo);
+                    UtxoKey::batch_put(&self.rocksdb, &mut batch, &utxo.key);
                 }
-                Undo::UtxoKeyPut(vout_script) => {
-                    UtxoKey::batch_delete(&self.rocksdb, &mut batch, &vout_script.key());
+                Coin::Spent(key) => {
+                    Utxo::batch_delete(&self.rocksdb, &mut batch, &key);
+                    UtxoKey::batch_delete(&self.rocksdb, &mut batch, &vout);
                 }
-                Undo::ScriptInfoPut(_) => {}
-                Undo::UtxoDelete(_) => {}
-                Undo::UtxoKeyDelete(_) => {}
-                Undo::ScriptInfoDelete(_) => {}
             }
         }
 
-        let block = Block {
-            height,
-            hash: U256::from_hex(&rpc_block.hash),
-            prev_hash: U256::from_hex(&rpc_block.previousblockhash.unwrap_or_else(|| {
-                "0000000000000000000000000000000000000000000000000000000000000000".to_string()
-            })),
-        };
+        for (_, info) in cache.infos.drain() {
+            ScriptInfo::batch_put(&self.rocksdb, &mut batch, &info);
+        }
+
+        for (block, block_undo) in cache.blocks.drain(..) {
+            Block::batch_put(&self.rocksdb, &mut batch, &block);
+            BlockUndo::batch_put(&self.rocksdb, &mut batch, &block_undo);
+        }
+
+        cache.size = 0;
+        self.rocksdb.write(batch).expect("Failed to flush blocks")
+    }
+
+    /// Put UTXOs loaded from a snapshot, accumulating their `ScriptInfo`.
+    /// No undo data is written, the UTXOs predate the tip.
+    pub fn load_utxos(&self, utxos: Vec<Utxo>) {
+        let mut batch = WriteBatch::default();
+        let mut infos = HashMap::<Vec<u8>, ScriptInfo>::new();
+
+        for utxo in utxos {
+            let info = infos.entry(utxo.key.script.clone()).or_insert_with(|| {
+                ScriptInfo::read(&self.rocksdb, ScriptRef(&utxo.key.script))
+                    .unwrap_or_else(|| ScriptInfo::new(&utxo.key.script))
+            });
+            info.add_unspent(utxo.value);
+
+            Utxo::batch_put(&self.rocksdb, &mut batch, &utxo);
+            UtxoKey::batch_put(&self.rocksdb, &mut batch, &utxo.key);
+        }
+
+        for (_, info) in infos {
+            ScriptInfo::batch_put(&self.rocksdb, &mut batch, &info);
+        }
+
+        self.rocksdb.write(batch).expect("Failed to load utxos")
+    }
+
+    /// Set the tip of a loaded snapshot, there is no undo data to pop it.
+    pub fn load_tip(&self, block: Block) {
+        let mut batch = WriteBatch::default();
         Block::batch_put(&self.rocksdb, &mut batch, &block);
+        self.rocksdb.write(batch).expect("Failed to load tip")
+    }
 
-        let block_undo = BlockUndo { height, vec: undos };
-        BlockUndo::batch_put(&self.rocksdb, &mut batch, &block_undo);
+    /// Delete everything to reindex from genesis.
+    /// `block` is cleared first so that an interrupted clear is left without a tip,
+    /// the index then clears again when it starts from genesis.
+    pub fn clear(&self) {
+        self.clear_cf::<Block>();
+        self.clear_cf::<BlockUndo>();
+        self.clear_cf::<Utxo>();
+        self.clear_cf::<UtxoKey>();
+        self.clear_cf::<ScriptInfo>();
+    }
 
-        self.rocksdb.write(batch).expect("Failed to push block")
+    fn clear_cf<D: CFStruct>(&self) {
+        let family = self.rocksdb.cf_handle(D::CF_NAME).unwrap();
+        let mut batch = WriteBatch::default();
+        let iter =
+            self.rocksdb
+                .iterator_cf_opt(family, ReadOptions::default(), IteratorMode::Start);
+        for item in iter {
+            let (key, _) = item.unwrap();
+            batch.delete_cf(family, &key);
+            if batch.len() >= 10_000 {
+                self.rocksdb
+                    .write(std::mem::take(&mut batch))
+                    .expect("Failed to clear");
+            }
+        }
+        self.rocksdb.write(batch).expect("Failed to clear");
+        tracing::info!("Cleared: {}", D::CF_NAME);
     }
 
+    /// Delete the blocks and their undo data below `height` with a range delete per column family,
+    /// in a single write. The space is reclaimed as they are compacted, see `compact_until`.
     pub fn prune_until(&self, height: u64) {
-        // TODO(fuxingloh): delete_range_cf isn't implemented for TransactionDB yet, unless we fork
-        //  the rocksdb crate and implement it ourselves.
-        let mut opts = ReadOptions::default();
-        opts.set_iterate_lower_bound(bincode::serialize(&0u64).unwrap());
-        opts.set_iterate_upper_bound(bincode::serialize(&height).unwrap());
+        let start = bincode::serialize(&0u64).unwrap();
+        let end = bincode::serialize(&height).unwrap();
+        let mut batch = WriteBatch::default();
+        for name in [Block::CF_NAME, BlockUndo::CF_NAME] {
+            let family = self.rocksdb.cf_handle(name).unwrap();
+            batch.delete_range_cf(family, &start, &end);
+        }
+        self.rocksdb.write(batch).expect("Failed to prune blocks");
+        tracing::info!("Pruned blocks: until {}", height);
+    }
 
-        let blocks = Block::iterator(&self.rocksdb, opts, IteratorMode::Start);
-        for block in blocks {
-            let mut batch = WriteBatchWithTransaction::default();
-            Block::batch_delete(&self.rocksdb, &mut batch, &block.key());
-            BlockUndo::batch_delete(&self.rocksdb, &mut batch, &block.key());
-            self.rocksdb.write(batch).expect("Failed to prune block");
-            tracing::info!("Pruned block: ({}, {})", block.height, block.hash.to_hex());
+    /// Compact the blocks and undo data below `height`, dropping what was pruned.
+    /// It blocks until the compaction is done, which can take a while.
+    pub fn compact_until(&self, height: u64) {
+        let end = bincode::serialize(&height).unwrap();
+        for name in [Block::CF_NAME, BlockUndo::CF_NAME] {
+            let family = self.rocksdb.cf_handle(name).unwrap();
+            self.rocksdb
+                .compact_range_cf(family, None::<&[u8]>, Some(end.as_slice()));
         }
+        tracing::info!("Compacted blocks: until {}", height);
     }
 
     fn get_utxo(&self, vout: &Vout) -> Utxo {
@@ -574,12 +1338,18 @@ impl Db {
         Utxo::read(&self.rocksdb, &vout_key).expect("Utxo not found")
     }
 
+    /// Unspent output by outpoint, `None` if it is unknown or already spent.
+    pub fn find_utxo(&self, vout: &Vout) -> Option<Utxo> {
+        let vout_key = UtxoKey::read(&self.rocksdb, vout)?;
+        Utxo::read(&self.rocksdb, &vout_key)
+    }
+
     pub fn get_block(&self, height: u64) -> Option<Block> {
         Block::read(&self.rocksdb, &height)
     }
 
     pub fn get_script_info(&self, script: &[u8]) -> Option<ScriptInfo> {
-        ScriptInfo::read(&self.rocksdb, script)
+        ScriptInfo::read(&self.rocksdb, ScriptRef(script))
     }
 
     pub fn iterator_script_utxo(
@@ -587,20 +1357,375 @@ impl Db {
         script: &[u8],
         upper_lower_bound: Range<Option<u64>>,
     ) -> CFIterator<Utxo> {
-        let mut opts = ReadOptions::default();
-        opts.set_prefix_same_as_start(true);
+        let (opts, prefix) = script_utxo_opts(script, upper_lower_bound);
+        let mode = IteratorMode::From(prefix.as_ref(), Direction::Forward);
+        Utxo::iterator(&self.rocksdb, opts, mode)
+    }
+}
+
+/// Read options bounding the UTXOs of `script` by height, and the key they start from.
+fn script_utxo_opts(
+    script: &[u8],
+    upper_lower_bound: Range<Option<u64>>,
+) -> (ReadOptions, Vec<u8>) {
+    let mut opts = ReadOptions::default();
+    opts.set_prefix_same_as_start(true);
+
+    if let Some(lower_bound) = upper_lower_bound.start {
+        let start = bincode::serialize(&(ScriptRef(script), lower_bound)).unwrap();
+        opts.set_iterate_lower_bound(start);
+    }
+    if let Some(upper_bound) = upper_lower_bound.end {
+        let end = bincode::serialize(&(ScriptRef(script), upper_bound)).unwrap();
+        opts.set_iterate_upper_bound(end);
+    }
 
-        if let Some(lower_bound) = upper_lower_bound.start {
-            let start = bincode::serialize(&(script, lower_bound)).unwrap();
-            opts.set_iterate_lower_bound(start);
+    let prefix = bincode::serialize(&ScriptRef(script)).unwrap();
+    (opts, prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::fixtures;
+
+    use super::*;
+
+    #[test]
+    fn script_key() {
+        let key = UtxoKey {
+            script: fixtures::p2wpkh(1).to_bytes(),
+            height: 7,
+            vout: Vout {
+                txid: U256::from([1; 32]),
+                n: 0,
+            },
+        };
+        let bytes = bincode::serialize(&key).unwrap();
+        // Tag and program, the prefix seeked by `iterator_script_utxo`
+        assert_eq!(script_key_len(&bytes), 21);
+        assert!(bytes.starts_with(&bincode::serialize(&ScriptRef(&key.script)).unwrap()));
+        let decoded: UtxoKey = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.script, key.script);
+        assert_eq!(decoded.vout, key.vout);
+        assert_eq!(decoded.height, 7);
+    }
+
+    #[test]
+    fn amount() {
+        let encode = |value: U128Decimal| bincode::serialize(&Amount(value)).unwrap();
+        let decode = |bytes: &[u8]| bincode::deserialize::<Amount>(bytes).unwrap().0;
+
+        // 50 BTC compressed to 50, shifted left
+        assert_eq!(encode(U128Decimal(5_000_000_000, 8)), [100]);
+        assert_eq!(decode(&[100]).0, 5_000_000_000);
+        // Other scales are equal in value, satoshis or not
+        let value = decode(&encode(U128Decimal(5, 0)));
+        assert_eq!((value.0, value.1), (500_000_000, 8));
+        for value in [U128Decimal(1, 18), U128Decimal(u128::MAX, 8)] {
+            let decoded = decode(&encode(value));
+            assert_eq!((decoded.0, decoded.1), (value.0, value.1));
+        }
+
+        // In a tuple, as the values of the column families
+        let bytes = bincode::serialize(&(Amount(U128Decimal(1, 18)), 7u64)).unwrap();
+        let (amount, n): (Amount, u64) = bincode::deserialize(&bytes).unwrap();
+        assert_eq!((amount.0 .0, n), (1, 7));
+    }
+
+    #[test]
+    fn pop_pruned() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path().to_str().unwrap());
+        for (height, block) in fixtures::regtest_chain(4).iter().enumerate() {
+            db.push(crate::block::Block::from_bitcoin(height as u64, block));
         }
-        if let Some(upper_bound) = upper_lower_bound.end {
-            let end = bincode::serialize(&(script, upper_bound)).unwrap();
-            opts.set_iterate_upper_bound(end);
+
+        // Tip left without undo data, as when a fork reaches below the pruned window
+        let mut batch = WriteBatch::default();
+        BlockUndo::batch_delete(&db.rocksdb, &mut batch, &3);
+        db.rocksdb.write(batch).unwrap();
+        assert!(matches!(db.pop(), Err(Error::UndoNotFound { height: 3 })));
+
+        db.clear();
+        assert!(db.peek().is_none());
+        assert!(matches!(db.pop(), Err(Error::Empty)));
+    }
+
+    #[test]
+    fn prune_until() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path().to_str().unwrap());
+        for (height, block) in fixtures::regtest_chain(6).iter().enumerate() {
+            db.push(crate::block::Block::from_bitcoin(height as u64, block));
         }
 
-        let prefix = bincode::serialize(&script).unwrap();
-        let mode = IteratorMode::From(prefix.as_ref(), Direction::Forward);
-        Utxo::iterator(&self.rocksdb, opts, mode)
+        db.prune_until(4);
+        db.compact_until(4);
+        assert!(db.get_block(3).is_none());
+        assert!(db.get_block(4).is_some());
+        assert_eq!(db.pop().unwrap().height, 5);
+        assert!(matches!(db.pop(), Err(Error::UndoNotFound { height: 3 })));
+    }
+
+    /// Regtest chain of 4 blocks, the last one spending the coinbase of block 1.
+    fn spending_chain() -> Vec<crate::block::Block> {
+        let mut chain = fixtures::regtest_chain(3);
+        let mut block = fixtures::regtest_block(&chain[2], 3, 0);
+        let spend = fixtures::spend(&chain[1].txdata[0], 0, fixtures::p2wpkh(2));
+        block.txdata.push(spend);
+        chain.push(block);
+        chain
+            .iter()
+            .enumerate()
+            .map(|(height, block)| crate::block::Block::from_bitcoin(height as u64, block))
+            .collect()
+    }
+
+    fn push_spending_chain(db: &Db) {
+        for block in spending_chain() {
+            db.push(block);
+        }
+    }
+
+    /// Verify `db` has no violations and its tip MuHash is the hash of its UTXO set.
+    fn assert_consistent(db: &Db) {
+        let snapshot = db.snapshot();
+        snapshot.verify(|violation| panic!("{}", violation));
+        let mut muhash = MuHash::default();
+        for utxo in snapshot.iterator_utxo() {
+            muhash.insert_utxo(&utxo);
+        }
+        assert!(snapshot.peek().unwrap().muhash.finalize() == muhash.finalize());
+    }
+
+    #[test]
+    fn snapshot_reads() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path().to_str().unwrap());
+        let mut chain = spending_chain();
+        let last = chain.pop().unwrap();
+        for block in chain {
+            db.push(block);
+        }
+
+        let snapshot = db.snapshot();
+        db.push(last);
+        // Block 3 spent the coinbase of block 1, still unspent at the snapshot's tip
+        let script = fixtures::p2wpkh(1).to_bytes();
+        let coinbase = snapshot.iterator_script_utxo(&script, None..None).next();
+        let vout = coinbase.expect("Coinbase not found").key.vout;
+        assert_eq!(snapshot.peek().unwrap().height, 2);
+        assert!(snapshot.find_utxo(&vout).is_some());
+        assert!(db.find_utxo(&vout).is_none());
+        assert_eq!(snapshot.get_script_info(&script).unwrap().tx_count, 1);
+        assert!(db.get_script_info(&script).is_none());
+    }
+
+    #[test]
+    fn push_muhash() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path().to_str().unwrap());
+        push_spending_chain(&db);
+
+        // Rolled forward block by block, same as hashing the whole set
+        assert_consistent(&db);
+        let muhash = db.peek().unwrap().muhash;
+        assert!(db.get_block(2).unwrap().muhash.finalize() != muhash.finalize());
+    }
+
+    #[test]
+    fn pop() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path().to_str().unwrap());
+        push_spending_chain(&db);
+        let script = fixtures::p2wpkh(1).to_bytes();
+        assert_eq!(db.iterator_script_utxo(&script, None..None).count(), 0);
+
+        let popped = db.pop().unwrap();
+        assert_eq!(popped.height, 3);
+        assert_eq!(db.peek().unwrap().height, 2);
+        assert!(db
+            .get_script_info(&fixtures::p2wpkh(3).to_bytes())
+            .is_none());
+        assert_eq!(db.iterator_script_utxo(&script, None..None).count(), 1);
+        assert_consistent(&db);
+
+        // Undo data that no longer matches the UTXO set is refused as a whole
+        let created = fixtures::p2wpkh(2).to_bytes();
+        let utxo = db
+            .iterator_script_utxo(&created, None..None)
+            .next()
+            .unwrap();
+        let mut batch = WriteBatch::default();
+        Utxo::batch_delete(&db.rocksdb, &mut batch, &utxo.key);
+        db.rocksdb.write(batch).unwrap();
+        assert!(matches!(
+            db.pop(),
+            Err(Error::UndoMismatch { height: 2, .. })
+        ));
+        assert_eq!(db.peek().unwrap().height, 2);
+    }
+
+    #[test]
+    fn cache() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path().to_str().unwrap());
+        let mut cache = Cache::new(usize::MAX);
+        for block in spending_chain() {
+            db.connect(&mut cache, block);
+        }
+        assert_eq!(cache.len(), 4);
+        assert!(db.peek().is_none());
+        db.flush(&mut cache);
+        assert!(cache.is_empty());
+        assert_consistent(&db);
+
+        // Same as pushing block by block, and popped with the same undo data
+        let other = tempdir().unwrap();
+        let other = Db::open(other.path().to_str().unwrap());
+        push_spending_chain(&other);
+        assert!(db.peek().unwrap().muhash.finalize() == other.peek().unwrap().muhash.finalize());
+        let script = fixtures::p2wpkh(1).to_bytes();
+        assert_eq!(db.iterator_script_utxo(&script, None..None).count(), 0);
+        db.pop().unwrap();
+        assert_eq!(db.iterator_script_utxo(&script, None..None).count(), 1);
+        assert_consistent(&db);
+    }
+
+    /// Re-runs this test in a child process aborted at each failpoint of `pop`,
+    /// the reopened database must be consistent at either side of the pop.
+    #[test]
+    fn pop_crash() {
+        if let Ok(path) = std::env::var("OXTU_FAILPOINT_DB") {
+            let db = Db::open(&path);
+            db.pop().unwrap();
+            return;
+        }
+
+        for (failpoint, height) in [("pop::before_write", 3), ("pop::after_write", 2)] {
+            let dir = tempdir().unwrap();
+            let path = dir.path().to_str().unwrap();
+            push_spending_chain(&Db::open(path));
+
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "db::tests::pop_crash", "--test-threads=1"])
+                .env("OXTU_FAILPOINT", failpoint)
+                .env("OXTU_FAILPOINT_DB", path)
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status()
+                .unwrap();
+            assert!(!status.success(), "{} not reached", failpoint);
+
+            let db = Db::open(path);
+            assert_eq!(db.peek().unwrap().height, height);
+            assert_consistent(&db);
+        }
+    }
+
+    #[test]
+    fn verify() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path().to_str().unwrap());
+        for (height, block) in fixtures::regtest_chain(4).iter().enumerate() {
+            db.push(crate::block::Block::from_bitcoin(height as u64, block));
+        }
+        let verified = db.snapshot().verify(|violation| panic!("{}", violation));
+        assert_eq!(verified.utxos, 4);
+        assert_eq!(verified.blocks, 4);
+
+        let utxo = db.snapshot().iterator_utxo().next().unwrap();
+        let mut batch = WriteBatch::default();
+        UtxoKey::batch_delete(&db.rocksdb, &mut batch, &utxo.key.vout);
+        // Genesis is the lowest block but nothing was pruned below it
+        BlockUndo::batch_delete(&db.rocksdb, &mut batch, &0);
+        BlockUndo::batch_delete(&db.rocksdb, &mut batch, &2);
+        ScriptInfo::batch_delete(&db.rocksdb, &mut batch, ScriptRef(&utxo.key.script));
+        db.rocksdb.write(batch).unwrap();
+
+        let mut violations = Vec::new();
+        let verified = db.snapshot().verify(|violation| violations.push(violation));
+        assert_eq!(verified.violations, 4);
+        assert!(matches!(violations[0], Violation::MissingUtxoKey(vout) if vout == utxo.key.vout));
+        assert!(matches!(violations[1], Violation::BalanceMismatch { .. }));
+        assert!(matches!(
+            violations[2],
+            Violation::MissingBlockUndo { height: 0 }
+        ));
+        assert!(matches!(
+            violations[3],
+            Violation::MissingBlockUndo { height: 2 }
+        ));
+
+        // Block 3 is at the pruning boundary, like the base of an imported snapshot
+        db.prune_until(3);
+        let mut batch = WriteBatch::default();
+        BlockUndo::batch_delete(&db.rocksdb, &mut batch, &3);
+        db.rocksdb.write(batch).unwrap();
+        let verified = db.snapshot().verify(|_| {});
+        assert_eq!(verified.blocks, 1);
+        assert_eq!(verified.violations, 2);
+    }
+
+    #[test]
+    fn load_snapshot() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path().to_str().unwrap());
+        let script = fixtures::p2wpkh(1).to_bytes();
+        let utxo = |n: u32| Utxo {
+            key: UtxoKey {
+                script: script.clone(),
+                height: 1,
+                vout: Vout {
+                    txid: U256::from([n as u8; 32]),
+                    n,
+                },
+            },
+            coinbase: false,
+            value: U128Decimal(100, 8),
+        };
+
+        // Across batches, the script info accumulates
+        db.load_utxos(vec![utxo(0), utxo(1)]);
+        db.load_utxos(vec![utxo(2)]);
+        db.load_tip(Block {
+            height: 5,
+            hash: U256::from([5; 32]),
+            prev_hash: U256::from([4; 32]),
+            muhash: MuHash::default(),
+        });
+
+        let info = db.get_script_info(&script).unwrap();
+        assert_eq!(info.balance.0, 300);
+        assert_eq!(info.tx_count, 3);
+        assert_eq!(db.iterator_script_utxo(&script, None..None).count(), 3);
+        assert!(db.find_utxo(&utxo(2).key.vout).is_some());
+        assert_eq!(db.peek().unwrap().height, 5);
+        assert!(matches!(db.pop(), Err(Error::UndoNotFound { height: 5 })));
+    }
+
+    #[test]
+    fn secondary() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data");
+        let db = Db::open(path.to_str().unwrap());
+        let chain = fixtures::regtest_chain(4);
+        for (height, block) in chain.iter().take(3).enumerate() {
+            db.push(crate::block::Block::from_bitcoin(height as u64, block));
+        }
+
+        let secondary = Db::open_secondary(
+            path.to_str().unwrap(),
+            dir.path().join("secondary").to_str().unwrap(),
+            &DbOptions::default(),
+        );
+        assert_eq!(secondary.peek().unwrap().height, 2);
+
+        db.push(crate::block::Block::from_bitcoin(3, &chain[3]));
+        secondary.try_catch_up().unwrap();
+        assert_eq!(secondary.peek().unwrap().height, 3);
+        assert!(std::panic::catch_unwind(|| secondary.pop()).is_err());
     }
 }
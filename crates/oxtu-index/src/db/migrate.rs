@@ -0,0 +1,617 @@
+//! Migrations of the column families from one schema version to the next.
+
+use rocksdb::{IteratorMode, ReadOptions, WriteBatch};
+
+use super::{
+    bincode, Block, BlockUndo, CFStruct, Db, ScriptInfo, ScriptKey, Undo, Utxo, UtxoKey, Vout,
+    MIGRATION_CF, SCHEMA_VERSION, VERSION,
+};
+use crate::muhash::MuHash;
+use crate::types::{U128Decimal, U256};
+
+/// Key of `meta` counting the completed phases of the running migration, see `rewrite_cf`.
+const PROGRESS: &str = "migration";
+
+/// Migrations by the version they migrate from, each one decodes its layout into the current one
+/// and records `SCHEMA_VERSION` when done.
+pub(super) const MIGRATIONS: [fn(&Db); 2] = [from_legacy, from_decimal_amounts];
+
+/// Layout before the version was recorded: scripts and amounts in full, blocks without a MuHash.
+mod legacy {
+    use serde::{Deserialize, Serialize};
+
+    use crate::db::{self, ScriptKey, Vout};
+    use crate::types::U128Decimal;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct UtxoKey {
+        pub script: Vec<u8>,
+        pub height: u64,
+        pub vout: Vout,
+    }
+
+    impl UtxoKey {
+        pub fn into_key(self) -> db::UtxoKey {
+            db::UtxoKey {
+                script: self.script,
+                height: self.height,
+                vout: self.vout,
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Utxo {
+        pub key: UtxoKey,
+        pub coinbase: bool,
+        pub value: U128Decimal,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct ScriptInfo {
+        pub script: Vec<u8>,
+        pub balance: U128Decimal,
+        pub total_sent: U128Decimal,
+        pub total_received: U128Decimal,
+        pub tx_count: u64,
+    }
+
+    /// Same variants as `Undo`, in the same order.
+    #[derive(Serialize, Deserialize)]
+    pub enum Undo {
+        UtxoPut(Utxo),
+        UtxoDelete(UtxoKey),
+        UtxoKeyPut(UtxoKey),
+        UtxoKeyDelete(Vout),
+        ScriptInfoPut(ScriptInfo),
+        ScriptInfoDelete(Vec<u8>),
+    }
+
+    impl Undo {
+        pub fn into_undo(self) -> db::Undo {
+            match self {
+                Undo::UtxoPut(utxo) => db::Undo::UtxoPut(db::Utxo {
+                    key: utxo.key.into_key(),
+                    coinbase: utxo.coinbase,
+                    value: utxo.value,
+                }),
+                Undo::UtxoDelete(key) => db::Undo::UtxoDelete(key.into_key()),
+                Undo::UtxoKeyPut(key) => db::Undo::UtxoKeyPut(key.into_key()),
+                Undo::UtxoKeyDelete(vout) => db::Undo::UtxoKeyDelete(vout),
+                Undo::ScriptInfoPut(info) => db::Undo::ScriptInfoPut(db::ScriptInfo {
+                    script: info.script,
+                    balance: info.balance,
+                    total_sent: info.total_sent,
+                    total_received: info.total_received,
+                    tx_count: info.tx_count,
+                }),
+                Undo::ScriptInfoDelete(script) => db::Undo::ScriptInfoDelete(ScriptKey(script)),
+            }
+        }
+    }
+}
+
+/// Layout of version 1: scripts compressed, amounts in full.
+mod v1 {
+    use serde::{Deserialize, Serialize};
+
+    use crate::db::{self, ScriptKey, UtxoKey, Vout};
+    use crate::types::U128Decimal;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Utxo {
+        pub key: UtxoKey,
+        pub coinbase: bool,
+        pub value: U128Decimal,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct ScriptInfo {
+        #[serde(with = "crate::db::script")]
+        pub script: Vec<u8>,
+        pub balance: U128Decimal,
+        pub total_sent: U128Decimal,
+        pub total_received: U128Decimal,
+        pub tx_count: u64,
+    }
+
+    /// Same variants as `Undo`, in the same order.
+    #[derive(Serialize, Deserialize)]
+    pub enum Undo {
+        UtxoPut(Utxo),
+        UtxoDelete(UtxoKey),
+        UtxoKeyPut(UtxoKey),
+        UtxoKeyDelete(Vout),
+        ScriptInfoPut(ScriptInfo),
+        ScriptInfoDelete(ScriptKey),
+    }
+
+    impl Undo {
+        pub fn into_undo(self) -> db::Undo {
+            match self {
+                Undo::UtxoPut(utxo) => db::Undo::UtxoPut(db::Utxo {
+                    key: utxo.key,
+                    coinbase: utxo.coinbase,
+                    value: utxo.value,
+                }),
+                Undo::UtxoDelete(key) => db::Undo::UtxoDelete(key),
+                Undo::UtxoKeyPut(key) => db::Undo::UtxoKeyPut(key),
+                Undo::UtxoKeyDelete(vout) => db::Undo::UtxoKeyDelete(vout),
+                Undo::ScriptInfoPut(info) => db::Undo::ScriptInfoPut(db::ScriptInfo {
+                    script: info.script,
+                    balance: info.balance,
+                    total_sent: info.total_sent,
+                    total_received: info.total_received,
+                    tx_count: info.tx_count,
+                }),
+                Undo::ScriptInfoDelete(script) => db::Undo::ScriptInfoDelete(script),
+            }
+        }
+    }
+}
+
+/// Re-encode the UTXO set and the undo data, then hash the UTXO set of every retained block.
+fn from_legacy(db: &Db) {
+    rewrite_cf(db, 0, Utxo::CF_NAME, |key, value| {
+        let key: legacy::UtxoKey = bincode::deserialize(key).unwrap();
+        let (coinbase, value): (bool, U128Decimal) = bincode::deserialize(value).unwrap();
+        Utxo {
+            key: key.into_key(),
+            coinbase,
+            value,
+        }
+        .encode()
+    });
+    rewrite_cf(db, 1, UtxoKey::CF_NAME, |key, value| {
+        let vout: Vout = bincode::deserialize(key).unwrap();
+        let (height, script): (u64, Vec<u8>) = bincode::deserialize(value).unwrap();
+        UtxoKey {
+            script,
+            height,
+            vout,
+        }
+        .encode()
+    });
+    rewrite_cf(db, 2, ScriptInfo::CF_NAME, |key, value| {
+        let script: Vec<u8> = bincode::deserialize(key).unwrap();
+        let (balance, total_sent, total_received, tx_count) = bincode::deserialize(value).unwrap();
+        ScriptInfo {
+            script,
+            balance,
+            total_sent,
+            total_received,
+            tx_count,
+        }
+        .encode()
+    });
+    rewrite_cf(db, 3, BlockUndo::CF_NAME, |key, value| {
+        let height: u64 = bincode::deserialize(key).unwrap();
+        let undos: Vec<legacy::Undo> = bincode::deserialize(value).unwrap();
+        let vec = undos.into_iter().map(legacy::Undo::into_undo).collect();
+        BlockUndo { height, vec }.encode()
+    });
+    hash_blocks(db);
+
+    let meta = db.rocksdb.cf_handle(super::META_CF).unwrap();
+    let mut batch = WriteBatch::default();
+    batch.delete_cf(meta, PROGRESS);
+    db.batch_put_meta(&mut batch, VERSION, &SCHEMA_VERSION);
+    db.rocksdb.write(batch).expect("Failed to migrate");
+}
+
+/// Rewrite the legacy blocks with the MuHash of their UTXO set: the tip's is hashed from the
+/// UTXO set, each parent's by undoing the block on it. Coins spent by a retained block are kept
+/// in `MIGRATION_CF` until the block creating them is undone.
+/// Each block is written with its coins, after a crash the walk resumes from the lowest one.
+fn hash_blocks(db: &Db) {
+    let blocks = db.rocksdb.cf_handle(Block::CF_NAME).unwrap();
+    let coins = db.rocksdb.cf_handle(MIGRATION_CF).unwrap();
+    let legacy_block = |height: u64| {
+        let key = bincode::serialize(&height).unwrap();
+        let value = db.rocksdb.get_pinned_cf(blocks, key).unwrap()?;
+        bincode::deserialize::<(U256, U256)>(&value).ok()
+    };
+    let lowest = match db
+        .rocksdb
+        .iterator_cf_opt(blocks, ReadOptions::default(), IteratorMode::Start)
+        .next()
+    {
+        Some(item) => bincode::deserialize::<u64>(&item.unwrap().0).unwrap(),
+        None => return,
+    };
+
+    // Legacy values don't decode as blocks, hashed ones don't decode as legacy values
+    let mut hashed = None;
+    for item in db
+        .rocksdb
+        .iterator_cf_opt(blocks, ReadOptions::default(), IteratorMode::End)
+    {
+        let (key, value) = item.unwrap();
+        if bincode::deserialize::<(U256, U256)>(&value).is_ok() {
+            break;
+        }
+        hashed = Some(Block::decode((&key, &value)));
+    }
+    let mut block = match hashed {
+        Some(block) => block,
+        None => {
+            let tip = db
+                .rocksdb
+                .iterator_cf_opt(blocks, ReadOptions::default(), IteratorMode::End)
+                .next()
+                .unwrap();
+            let height: u64 = bincode::deserialize(&tip.unwrap().0).unwrap();
+            let (hash, prev_hash) = legacy_block(height).unwrap();
+            let mut muhash = MuHash::default();
+            let utxos = Utxo::iterator(&db.rocksdb, ReadOptions::default(), IteratorMode::Start);
+            for utxo in utxos {
+                muhash.insert_utxo(&utxo);
+            }
+            let block = Block {
+                height,
+                hash,
+                prev_hash,
+                muhash,
+            };
+            let mut batch = WriteBatch::default();
+            Block::batch_put(&db.rocksdb, &mut batch, &block);
+            db.rocksdb.write(batch).expect("Failed to migrate");
+            block
+        }
+    };
+
+    while block.height > lowest {
+        let Some(block_undo) = BlockUndo::read(&db.rocksdb, &block.height) else {
+            // Neither the block nor the ones below can be disconnected
+            db.prune_until(block.height);
+            break;
+        };
+        let (hash, prev_hash) = legacy_block(block.height - 1).unwrap();
+        let mut muhash = block.muhash;
+        let mut batch = WriteBatch::default();
+        for undo in block_undo.vec {
+            match undo {
+                Undo::UtxoPut(utxo) => {
+                    muhash.insert_utxo(&utxo);
+                    if utxo.key.height > lowest {
+                        let (key, value) = utxo.encode();
+                        batch.put_cf(coins, key, value);
+                    }
+                }
+                Undo::UtxoDelete(key) => {
+                    let utxo = Utxo::read(&db.rocksdb, &key).unwrap_or_else(|| {
+                        // Spent by a block above
+                        let key = bincode::serialize(&key).unwrap();
+                        let value = db.rocksdb.get_pinned_cf(coins, &key).unwrap();
+                        batch.delete_cf(coins, &key);
+                        Utxo::decode((&key, &value.expect("Spent coin not found")))
+                    });
+                    muhash.remove_utxo(&utxo);
+                }
+                _ => {}
+            }
+        }
+        block = Block {
+            height: block.height - 1,
+            hash,
+            prev_hash,
+            muhash,
+        };
+        Block::batch_put(&db.rocksdb, &mut batch, &block);
+        db.rocksdb.write(batch).expect("Failed to migrate");
+    }
+
+    let mut batch = WriteBatch::default();
+    for item in db
+        .rocksdb
+        .iterator_cf_opt(coins, ReadOptions::default(), IteratorMode::Start)
+    {
+        batch.delete_cf(coins, item.unwrap().0);
+    }
+    db.rocksdb.write(batch).expect("Failed to migrate");
+    tracing::info!("Migrated: {}", Block::CF_NAME);
+}
+
+/// Re-encode the amounts of the UTXO set, the script infos and the undo data.
+fn from_decimal_amounts(db: &Db) {
+    rewrite_cf(db, 0, Utxo::CF_NAME, |key, value| {
+        let key: UtxoKey = bincode::deserialize(key).unwrap();
+        let (coinbase, value) = bincode::deserialize(value).unwrap();
+        Utxo {
+            key,
+            coinbase,
+            value,
+        }
+        .encode()
+    });
+    rewrite_cf(db, 1, ScriptInfo::CF_NAME, |key, value| {
+        let ScriptKey(script) = bincode::deserialize(key).unwrap();
+        let (balance, total_sent, total_received, tx_count) = bincode::deserialize(value).unwrap();
+        ScriptInfo {
+            script,
+            balance,
+            total_sent,
+            total_received,
+            tx_count,
+        }
+        .encode()
+    });
+    rewrite_cf(db, 2, BlockUndo::CF_NAME, |key, value| {
+        let height: u64 = bincode::deserialize(key).unwrap();
+        let undos: Vec<v1::Undo> = bincode::deserialize(value).unwrap();
+        let vec = undos.into_iter().map(v1::Undo::into_undo).collect();
+        BlockUndo { height, vec }.encode()
+    });
+
+    let meta = db.rocksdb.cf_handle(super::META_CF).unwrap();
+    let mut batch = WriteBatch::default();
+    batch.delete_cf(meta, PROGRESS);
+    db.batch_put_meta(&mut batch, VERSION, &SCHEMA_VERSION);
+    db.rocksdb.write(batch).expect("Failed to migrate");
+}
+
+/// Rewrite every entry of `name` with `convert`, as the step `step` of a migration.
+/// Entries are converted into `MIGRATION_CF` then moved back, deleted from where they are moved
+/// from in the same batches: after a crash, each phase resumes with the entries it has left.
+fn rewrite_cf(
+    db: &Db,
+    step: u32,
+    name: &str,
+    convert: impl Fn(&[u8], &[u8]) -> (Vec<u8>, Vec<u8>),
+) {
+    let done = db.read_meta::<u32>(PROGRESS).unwrap_or(0);
+    if done < 2 * step + 1 {
+        move_cf(db, name, MIGRATION_CF, &convert, 2 * step + 1);
+    }
+    if done < 2 * step + 2 {
+        let unchanged = |key: &[u8], value: &[u8]| (key.to_vec(), value.to_vec());
+        move_cf(db, MIGRATION_CF, name, unchanged, 2 * step + 2);
+    }
+    tracing::info!("Migrated: {}", name);
+}
+
+/// Move every entry of `from` to `to`, the last batch records `done` phases.
+fn move_cf(
+    db: &Db,
+    from: &str,
+    to: &str,
+    convert: impl Fn(&[u8], &[u8]) -> (Vec<u8>, Vec<u8>),
+    done: u32,
+) {
+    let from = db.rocksdb.cf_handle(from).unwrap();
+    let to = db.rocksdb.cf_handle(to).unwrap();
+    let mut batch = WriteBatch::default();
+    let iter = db
+        .rocksdb
+        .iterator_cf_opt(from, ReadOptions::default(), IteratorMode::Start);
+    for item in iter {
+        let (key, value) = item.unwrap();
+        let (new_key, new_value) = convert(&key, &value);
+        batch.delete_cf(from, &key);
+        batch.put_cf(to, &new_key, &new_value);
+        if batch.len() >= 20_000 {
+            db.rocksdb
+                .write(std::mem::take(&mut batch))
+                .expect("Failed to migrate");
+        }
+    }
+    db.batch_put_meta(&mut batch, PROGRESS, &done);
+    db.rocksdb.write(batch).expect("Failed to migrate");
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::super::{META_CF, SCHEMA_VERSION};
+    use super::*;
+    use crate::fixtures;
+
+    fn ser<T: serde::Serialize>(value: &T) -> Vec<u8> {
+        bincode::serialize(value).unwrap()
+    }
+
+    fn put(db: &Db, cf: &str, key: Vec<u8>, value: Vec<u8>) {
+        let mut batch = WriteBatch::default();
+        batch.put_cf(db.rocksdb.cf_handle(cf).unwrap(), key, value);
+        db.rocksdb.write(batch).unwrap();
+    }
+
+    #[test]
+    fn legacy() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let value = U128Decimal(5_000_000_000, 8);
+        let vout = Vout {
+            txid: U256::from([1; 32]),
+            n: 0,
+        };
+        // Coinbase of block 1, spent by block 2 to `spent`
+        let coinbase = legacy::Utxo {
+            key: legacy::UtxoKey {
+                script: fixtures::p2wpkh(1).to_bytes(),
+                height: 1,
+                vout,
+            },
+            coinbase: true,
+            value,
+        };
+        let spent = legacy::UtxoKey {
+            script: fixtures::p2wpkh(2).to_bytes(),
+            height: 2,
+            vout: Vout {
+                txid: U256::from([2; 32]),
+                n: 0,
+            },
+        };
+        let info = |key: &legacy::UtxoKey, balance, total_sent, tx_count| legacy::ScriptInfo {
+            script: key.script.clone(),
+            balance,
+            total_sent,
+            total_received: value,
+            tx_count,
+        };
+        let copy = |key: &legacy::UtxoKey| legacy::UtxoKey {
+            script: key.script.clone(),
+            height: key.height,
+            vout: key.vout,
+        };
+        {
+            let db = Db::open(path);
+            put(&db, Utxo::CF_NAME, ser(&spent), ser(&(false, value)));
+            put(
+                &db,
+                UtxoKey::CF_NAME,
+                ser(&spent.vout),
+                ser(&(2u64, &spent.script)),
+            );
+            let infos = [
+                info(&coinbase.key, U128Decimal::zero(), value, 2u64),
+                info(&spent, value, U128Decimal::zero(), 1u64),
+            ];
+            for info in infos {
+                let value = (
+                    info.balance,
+                    info.total_sent,
+                    info.total_received,
+                    info.tx_count,
+                );
+                put(&db, ScriptInfo::CF_NAME, ser(&info.script), ser(&value));
+            }
+
+            let undos = [
+                vec![],
+                vec![
+                    legacy::Undo::ScriptInfoDelete(coinbase.key.script.clone()),
+                    legacy::Undo::UtxoDelete(copy(&coinbase.key)),
+                    legacy::Undo::UtxoKeyDelete(coinbase.key.vout),
+                ],
+                vec![
+                    legacy::Undo::ScriptInfoPut(info(&coinbase.key, value, U128Decimal::zero(), 1)),
+                    legacy::Undo::UtxoKeyPut(copy(&coinbase.key)),
+                    legacy::Undo::UtxoPut(coinbase),
+                    legacy::Undo::ScriptInfoDelete(spent.script.clone()),
+                    legacy::Undo::UtxoDelete(copy(&spent)),
+                    legacy::Undo::UtxoKeyDelete(spent.vout),
+                ],
+            ];
+            for (height, undo) in undos.iter().enumerate() {
+                let height = height as u64;
+                let hash = (
+                    U256::from([height as u8 + 1; 32]),
+                    U256::from([height as u8; 32]),
+                );
+                put(&db, Block::CF_NAME, ser(&height), ser(&hash));
+                put(&db, BlockUndo::CF_NAME, ser(&height), ser(undo));
+            }
+            let mut batch = WriteBatch::default();
+            batch.delete_cf(db.rocksdb.cf_handle(META_CF).unwrap(), VERSION);
+            db.rocksdb.write(batch).unwrap();
+        }
+
+        let db = Db::open(path);
+        assert_eq!(db.schema_version(), SCHEMA_VERSION);
+        let utxo = db.find_utxo(&spent.vout).unwrap();
+        assert!(!utxo.coinbase);
+        assert_eq!(utxo.key.script, spent.script);
+        assert_eq!(
+            db.get_script_info(&spent.script).unwrap().balance.0,
+            5_000_000_000
+        );
+        db.snapshot().verify(|violation| panic!("{}", violation));
+
+        let mut muhash = MuHash::default();
+        muhash.insert_utxo(&utxo);
+        assert!(db.peek().unwrap().muhash.finalize() == muhash.finalize());
+
+        // Undo data is kept, every block down to genesis can be disconnected
+        assert_eq!(db.pop().unwrap().height, 2);
+        let utxo = db.find_utxo(&vout).unwrap();
+        assert!(utxo.coinbase);
+        let mut muhash = MuHash::default();
+        muhash.insert_utxo(&utxo);
+        assert!(db.peek().unwrap().muhash.finalize() == muhash.finalize());
+        assert_eq!(
+            db.get_script_info(&utxo.key.script).unwrap().balance.0,
+            5_000_000_000
+        );
+        assert!(db.get_script_info(&spent.script).is_none());
+
+        assert_eq!(db.pop().unwrap().height, 1);
+        assert!(db.peek().unwrap().muhash.finalize() == MuHash::default().finalize());
+        assert!(db.snapshot().iterator_utxo().next().is_none());
+    }
+
+    #[test]
+    fn decimal_amounts() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let script = fixtures::p2wpkh(1).to_bytes();
+        let key = UtxoKey {
+            script: script.clone(),
+            height: 1,
+            vout: Vout {
+                txid: U256::from([1; 32]),
+                n: 0,
+            },
+        };
+        let value = U128Decimal(5_000_000_000, 8);
+        {
+            let db = Db::open(path);
+            put(&db, Utxo::CF_NAME, ser(&key), ser(&(true, value)));
+            let script_key = ScriptKey(script.clone());
+            put(
+                &db,
+                UtxoKey::CF_NAME,
+                ser(&key.vout),
+                ser(&(1u64, &script_key)),
+            );
+            let info = (value, U128Decimal::zero(), value, 1u64);
+            put(&db, ScriptInfo::CF_NAME, ser(&script_key), ser(&info));
+            let undo = vec![
+                v1::Undo::ScriptInfoDelete(script_key),
+                v1::Undo::UtxoDelete(key.clone()),
+                v1::Undo::UtxoKeyDelete(key.vout),
+            ];
+            put(&db, BlockUndo::CF_NAME, ser(&1u64), ser(&undo));
+
+            let mut batch = WriteBatch::default();
+            for height in 0..2u64 {
+                let block = Block {
+                    height,
+                    hash: U256::from([height as u8 + 1; 32]),
+                    prev_hash: U256::from([height as u8; 32]),
+                    muhash: MuHash::default(),
+                };
+                Block::batch_put(&db.rocksdb, &mut batch, &block);
+            }
+            db.batch_put_meta(&mut batch, VERSION, &1u32);
+            db.rocksdb.write(batch).unwrap();
+        }
+
+        let db = Db::open(path);
+        assert_eq!(db.schema_version(), SCHEMA_VERSION);
+        assert_eq!(db.find_utxo(&key.vout).unwrap().value.0, 5_000_000_000);
+        assert_eq!(
+            db.get_script_info(&script).unwrap().total_received.0,
+            5_000_000_000
+        );
+        // Undo data is kept, the block can still be disconnected
+        assert_eq!(db.pop().unwrap().height, 1);
+        assert!(db.find_utxo(&key.vout).is_none());
+        assert!(db.get_script_info(&script).is_none());
+    }
+
+    #[test]
+    fn unknown_version() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        {
+            let db = Db::open(path);
+            let mut batch = WriteBatch::default();
+            db.batch_put_meta(&mut batch, VERSION, &(SCHEMA_VERSION + 1));
+            db.rocksdb.write(batch).unwrap();
+        }
+        assert!(std::panic::catch_unwind(|| Db::open(path)).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//! RocksDB tuning of the database and of each column family.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use rocksdb::{BlockBasedOptions, Cache, DBCompressionType, Options};
+
+#[derive(Debug, Clone)]
+pub struct DbOptions {
+    /// Size in bytes of the LRU block cache shared by the column families.
+    pub block_cache_size: usize,
+    /// Maximum number of open files, `-1` keeps them all open.
+    pub max_open_files: i32,
+    /// Tuning of the column families by name, each one falls back to the defaults of its access pattern.
+    pub cfs: HashMap<String, CfOptions>,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        Self {
+            block_cache_size: 256 << 20,
+            max_open_files: -1,
+            cfs: HashMap::new(),
+        }
+    }
+}
+
+impl DbOptions {
+    /// `Options` of the column family `name`, `defaults` for what isn't set in `cfs`.
+    pub(super) fn cf_options(&self, name: &str, defaults: CfOptions, cache: &Cache) -> Options {
+        let tuning = match self.cfs.get(name) {
+            Some(tuning) => tuning.clone().or(defaults),
+            None => defaults,
+        };
+
+        let mut table = BlockBasedOptions::default();
+        table.set_block_cache(cache);
+        if let Some(bits) = tuning.bloom_bits.filter(|&bits| bits > 0.0) {
+            table.set_bloom_filter(bits, false);
+        }
+
+        let mut options = Options::default();
+        options.set_block_based_table_factory(&table);
+        if let Some(compression) = tuning.compression {
+            let levels: Vec<_> = compression
+                .into_iter()
+                .map(DBCompressionType::from)
+                .collect();
+            options.set_compression_per_level(&levels);
+        }
+        if let Some(size) = tuning.write_buffer_size {
+            options.set_write_buffer_size(size);
+        }
+        options
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CfOptions {
+    /// Bits per key of the bloom filter for point lookups (of prefixes with a prefix extractor),
+    /// `0` disables it.
+    pub bloom_bits: Option<f64>,
+    /// Compression from L0, the last one applies to the deeper levels.
+    pub compression: Option<Vec<Compression>>,
+    /// Size in bytes of a memtable before it is flushed.
+    pub write_buffer_size: Option<usize>,
+}
+
+impl CfOptions {
+    /// `self`, falling back to `defaults` for what isn't set.
+    pub fn or(self, defaults: CfOptions) -> CfOptions {
+        CfOptions {
+            bloom_bits: self.bloom_bits.or(defaults.bloom_bits),
+            compression: self.compression.or(defaults.compression),
+            write_buffer_size: self.write_buffer_size.or(defaults.write_buffer_size),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+    Zlib,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(Compression::None),
+            "snappy" => Ok(Compression::Snappy),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            "zlib" => Ok(Compression::Zlib),
+            _ => Err(format!("Unknown compression: {}", value)),
+        }
+    }
+}
+
+impl From<Compression> for DBCompressionType {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::None => DBCompressionType::None,
+            Compression::Snappy => DBCompressionType::Snappy,
+            Compression::Lz4 => DBCompressionType::Lz4,
+            Compression::Zstd => DBCompressionType::Zstd,
+            Compression::Zlib => DBCompressionType::Zlib,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cf_options_or() {
+        let defaults = CfOptions {
+            bloom_bits: Some(10.0),
+            compression: Some(vec![Compression::Lz4]),
+            write_buffer_size: None,
+        };
+        let tuning = CfOptions {
+            bloom_bits: Some(0.0),
+            write_buffer_size: Some(1 << 20),
+            ..Default::default()
+        };
+        assert_eq!(
+            tuning.or(defaults),
+            CfOptions {
+                bloom_bits: Some(0.0),
+                compression: Some(vec![Compression::Lz4]),
+                write_buffer_size: Some(1 << 20),
+            }
+        );
+        assert_eq!("zstd".parse(), Ok(Compression::Zstd));
+        assert!("brotli".parse::<Compression>().is_err());
+    }
+}
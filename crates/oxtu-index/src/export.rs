@@ -0,0 +1,307 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use bigdecimal::BigDecimal;
+use serde::Serialize;
+
+use crate::compress::{write_compact_size, write_varint};
+use crate::db::{Db, ScriptInfo, Utxo};
+use crate::types::{U128Decimal, U256};
+
+const MAGIC: [u8; 4] = *b"oxtu";
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Compact binary records, see `Writer`.
+    #[default]
+    Binary,
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Binary => "bin",
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "binary" => Ok(Format::Binary),
+            "csv" => Ok(Format::Csv),
+            "ndjson" => Ok(Format::Ndjson),
+            _ => Err(format!("Unknown export format: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// There is no block indexed yet.
+    Empty,
+    /// The export directory already exists.
+    Exists(PathBuf),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+/// Written last as `manifest.json`, an export without it is incomplete.
+#[derive(Serialize, Debug)]
+pub struct Manifest {
+    pub version: u8,
+    pub height: u64,
+    pub hash: String,
+    pub utxos: u64,
+    pub script_infos: u64,
+}
+
+/// Export every `Utxo` and `ScriptInfo` at a consistent snapshot of `db` to `dir`,
+/// as `utxo.<ext>` and `script_info.<ext>` then `manifest.json`. `dir` must not exist yet.
+pub fn export(db: &Db, dir: &Path, format: Format) -> Result<Manifest, Error> {
+    // A manifest left by a previous export would vouch for incomplete files
+    if dir.exists() {
+        return Err(Error::Exists(dir.to_path_buf()));
+    }
+    let snapshot = db.snapshot();
+    let tip = snapshot.peek().ok_or(Error::Empty)?;
+    fs::create_dir_all(dir)?;
+
+    let create = |name: &str| -> io::Result<Writer<BufWriter<File>>> {
+        let path = dir.join(format!("{}.{}", name, format.extension()));
+        let file = BufWriter::with_capacity(1 << 20, File::create(path)?);
+        Ok(Writer {
+            inner: file,
+            format,
+        })
+    };
+
+    let mut writer = create("utxo")?;
+    writer.header(Kind::Utxo, tip.height, &tip.hash)?;
+    let mut utxos = 0u64;
+    for utxo in snapshot.iterator_utxo() {
+        writer.utxo(&utxo)?;
+        utxos += 1;
+        if utxos % 1_000_000 == 0 {
+            tracing::info!("Exported utxo: {}", utxos);
+        }
+    }
+    writer.inner.flush()?;
+
+    let mut writer = create("script_info")?;
+    writer.header(Kind::ScriptInfo, tip.height, &tip.hash)?;
+    let mut script_infos = 0u64;
+    for info in snapshot.iterator_script_info() {
+        writer.script_info(&info)?;
+        script_infos += 1;
+        if script_infos % 1_000_000 == 0 {
+            tracing::info!("Exported script_info: {}", script_infos);
+        }
+    }
+    writer.inner.flush()?;
+
+    let manifest = Manifest {
+        version: VERSION,
+        height: tip.height,
+        hash: tip.hash.to_hex(),
+        utxos,
+        script_infos,
+    };
+    let file = File::create(dir.join("manifest.json"))?;
+    serde_json::to_writer_pretty(file, &manifest).map_err(io::Error::from)?;
+    tracing::info!("Exported: {:?}", &manifest);
+    Ok(manifest)
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Utxo = 0,
+    ScriptInfo = 1,
+}
+
+/// Writer of export records in `format`.
+///
+/// The binary format uses Bitcoin Core's serialization primitives,
+/// `VARINT` for integers and `CompactSize` prefixed scripts, hashes are in display order:
+/// - header: `oxtu` magic, `u8` version, `u8` kind (0 utxo, 1 script_info), `u64` LE height, tip hash
+/// - utxo: `[txid][VARINT n][VARINT height << 1 | coinbase][decimal value][script]`
+/// - script_info: `[script][decimal balance][decimal total_sent][decimal total_received][VARINT tx_count]`
+///
+/// where decimals are `[VARINT mantissa][u8 scale]`, records follow until the end of the file.
+struct Writer<W> {
+    inner: W,
+    format: Format,
+}
+
+impl<W: Write> Writer<W> {
+    fn header(&mut self, kind: Kind, height: u64, hash: &U256) -> io::Result<()> {
+        match self.format {
+            Format::Binary => {
+                self.inner.write_all(&MAGIC)?;
+                self.inner.write_all(&[VERSION, kind as u8])?;
+                self.inner.write_all(&height.to_le_bytes())?;
+                self.inner.write_all(hash.as_bytes())
+            }
+            Format::Csv => match kind {
+                Kind::Utxo => writeln!(self.inner, "script,txid,vout,height,coinbase,value"),
+                Kind::ScriptInfo => writeln!(
+                    self.inner,
+                    "script,balance,total_sent,total_received,tx_count"
+                ),
+            },
+            Format::Ndjson => Ok(()),
+        }
+    }
+
+    fn utxo(&mut self, utxo: &Utxo) -> io::Result<()> {
+        let key = &utxo.key;
+        match self.format {
+            Format::Binary => {
+                self.inner.write_all(key.vout.txid.as_bytes())?;
+                write_varint(&mut self.inner, key.vout.n as u128)?;
+                write_varint(
+                    &mut self.inner,
+                    (key.height as u128) << 1 | utxo.coinbase as u128,
+                )?;
+                self.decimal(utxo.value)?;
+                self.script(&key.script)
+            }
+            Format::Csv => writeln!(
+                self.inner,
+                "{},{},{},{},{},{}",
+                hex::encode(&key.script),
+                key.vout.txid,
+                key.vout.n,
+                key.height,
+                utxo.coinbase,
+                BigDecimal::from(utxo.value)
+            ),
+            Format::Ndjson => self.json(&serde_json::json!({
+                "script": hex::encode(&key.script),
+                "txid": key.vout.txid.to_hex(),
+                "vout": key.vout.n,
+                "height": key.height,
+                "coinbase": utxo.coinbase,
+                "value": BigDecimal::from(utxo.value).to_string(),
+            })),
+        }
+    }
+
+    fn script_info(&mut self, info: &ScriptInfo) -> io::Result<()> {
+        match self.format {
+            Format::Binary => {
+                self.script(&info.script)?;
+                self.decimal(info.balance)?;
+                self.decimal(info.total_sent)?;
+                self.decimal(info.total_received)?;
+                write_varint(&mut self.inner, info.tx_count as u128)
+            }
+            Format::Csv => writeln!(
+                self.inner,
+                "{},{},{},{},{}",
+                hex::encode(&info.script),
+                BigDecimal::from(info.balance),
+                BigDecimal::from(info.total_sent),
+                BigDecimal::from(info.total_received),
+                info.tx_count
+            ),
+            Format::Ndjson => self.json(&serde_json::json!({
+                "script": hex::encode(&info.script),
+                "balance": BigDecimal::from(info.balance).to_string(),
+                "total_sent": BigDecimal::from(info.total_sent).to_string(),
+                "total_received": BigDecimal::from(info.total_received).to_string(),
+                "tx_count": info.tx_count,
+            })),
+        }
+    }
+
+    fn decimal(&mut self, value: U128Decimal) -> io::Result<()> {
+        write_varint(&mut self.inner, value.0)?;
+        self.inner.write_all(&[value.1])
+    }
+
+    fn script(&mut self, script: &[u8]) -> io::Result<()> {
+        write_compact_size(&mut self.inner, script.len() as u64)?;
+        self.inner.write_all(script)
+    }
+
+    fn json(&mut self, value: &serde_json::Value) -> io::Result<()> {
+        serde_json::to_writer(&mut self.inner, value)?;
+        self.inner.write_all(b"\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{UtxoKey, Vout};
+
+    use super::*;
+
+    fn utxo() -> Utxo {
+        Utxo {
+            key: UtxoKey {
+                script: vec![0x51],
+                height: 300,
+                vout: Vout {
+                    txid: U256::from([0xab; 32]),
+                    n: 1,
+                },
+            },
+            coinbase: true,
+            value: U128Decimal(5_000_000_000, 8),
+        }
+    }
+
+    fn write(format: Format) -> Vec<u8> {
+        let mut writer = Writer {
+            inner: Vec::new(),
+            format,
+        };
+        writer.header(Kind::Utxo, 300, &U256::zero()).unwrap();
+        writer.utxo(&utxo()).unwrap();
+        writer.inner
+    }
+
+    #[test]
+    fn binary() {
+        let bytes = write(Format::Binary);
+        // magic, version, kind, height, hash
+        assert_eq!(bytes[..6], [b'o', b'x', b't', b'u', VERSION, 0]);
+        let record = &bytes[6 + 8 + 32..];
+        assert_eq!(record[..32], [0xab; 32]);
+        // n, (300 << 1 | 1) as VARINT, value as VARINT, scale, script
+        assert_eq!(record[32], 1);
+        assert_eq!(record[33..35], [0x83, 0x59]);
+        assert_eq!(record[record.len() - 3..], [8, 1, 0x51]);
+    }
+
+    #[test]
+    fn text() {
+        let csv = String::from_utf8(write(Format::Csv)).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "script,txid,vout,height,coinbase,value");
+        assert_eq!(
+            lines[1],
+            format!("51,{},1,300,true,50.00000000", "ab".repeat(32))
+        );
+
+        let ndjson = String::from_utf8(write(Format::Ndjson)).unwrap();
+        let value: serde_json::Value = serde_json::from_str(ndjson.trim_end()).unwrap();
+        assert_eq!(value["value"], "50.00000000");
+        assert_eq!(value["height"], 300);
+    }
+}
@@ -0,0 +1,137 @@
+//! Regtest blocks built in-process for tests that don't need a running bitcoind.
+
+use std::sync::Mutex;
+
+use bitcoin::absolute::LockTime;
+use bitcoin::block::{Header, Version};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::hashes::Hash;
+use bitcoin::script::PushBytesBuf;
+use bitcoin::{
+    transaction, Amount, Block, CompactTarget, Network, OutPoint, ScriptBuf, Sequence, Transaction,
+    TxIn, TxMerkleNode, TxOut, Witness,
+};
+
+use crate::block::{self, to_u256};
+use crate::source::{self, BlockSource, BoxFuture};
+use crate::types::U256;
+
+/// In-memory `BlockSource` serving `chain`.
+pub struct MockSource {
+    chain: Mutex<Vec<Block>>,
+}
+
+impl MockSource {
+    pub fn new(chain: Vec<Block>) -> MockSource {
+        MockSource {
+            chain: Mutex::new(chain),
+        }
+    }
+
+    /// Replace the served chain, e.g. with a fork of it.
+    pub fn set_chain(&self, chain: Vec<Block>) {
+        *self.chain.lock().unwrap() = chain;
+    }
+}
+
+impl BlockSource for MockSource {
+    fn get_blockhash(&self, height: u64) -> BoxFuture<'_, Result<U256, source::Error>> {
+        let chain = self.chain.lock().unwrap();
+        let hash = chain
+            .get(height as usize)
+            .map(|block| to_u256(block.block_hash()));
+        Box::pin(async move { hash.ok_or(source::Error::NotFound) })
+    }
+
+    fn get_block(
+        &self,
+        hash: U256,
+        height: u64,
+    ) -> BoxFuture<'_, Result<block::Block, source::Error>> {
+        let chain = self.chain.lock().unwrap();
+        let block = chain
+            .iter()
+            .find(|block| to_u256(block.block_hash()) == hash)
+            .map(|block| block::Block::from_bitcoin(height, block));
+        Box::pin(async move { block.ok_or(source::Error::NotFound) })
+    }
+
+    fn get_block_count(&self) -> BoxFuture<'_, Result<u64, source::Error>> {
+        let count = self.chain.lock().unwrap().len() as u64 - 1;
+        Box::pin(async move { Ok(count) })
+    }
+}
+
+/// Regtest chain of `len` blocks starting from the regtest genesis block.
+pub fn regtest_chain(len: usize) -> Vec<Block> {
+    let mut chain = vec![genesis_block(Network::Regtest)];
+    while chain.len() < len {
+        let height = chain.len() as u32;
+        chain.push(regtest_block(chain.last().unwrap(), height, 0));
+    }
+    chain
+}
+
+/// Block on top of `prev` with a single coinbase paying 50 BTC to a P2WPKH script,
+/// `tag` is committed in the coinbase to create competing blocks at the same height.
+/// Proof of work is solved at the regtest target, which about half of all hashes meet.
+pub fn regtest_block(prev: &Block, height: u32, tag: u8) -> Block {
+    let mut script_sig = ScriptBuf::builder().push_int(height as i64);
+    script_sig = script_sig.push_slice(PushBytesBuf::from([tag]));
+    let coinbase = Transaction {
+        version: transaction::Version::ONE,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: script_sig.into_script(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::from_btc(50.0).unwrap(),
+            script_pubkey: p2wpkh(height as u8),
+        }],
+    };
+
+    let mut block = Block {
+        header: Header {
+            version: Version::ONE,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: prev.header.time + 600,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        },
+        txdata: vec![coinbase],
+    };
+    block.header.merkle_root = block.compute_merkle_root().unwrap();
+    while block.header.validate_pow(block.header.target()).is_err() {
+        block.header.nonce += 1;
+    }
+    block
+}
+
+/// Transaction spending output `n` of `prev` entirely to `script`, fees are not accounted for.
+pub fn spend(prev: &Transaction, n: u32, script: ScriptBuf) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(prev.compute_txid(), n),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: prev.output[n as usize].value,
+            script_pubkey: script,
+        }],
+    }
+}
+
+/// P2WPKH script of a fake 20-byte program filled with `fill`.
+pub fn p2wpkh(fill: u8) -> ScriptBuf {
+    let mut bytes = vec![0x00, 0x14];
+    bytes.extend_from_slice(&[fill; 20]);
+    ScriptBuf::from_bytes(bytes)
+}
@@ -1,26 +1,152 @@
+use std::collections::VecDeque;
 use std::fmt;
+use std::panic;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-use tokio::sync::watch;
-use tokio::task::spawn;
+use tokio::sync::{watch, Notify, Semaphore};
+use tokio::task::{spawn, spawn_blocking, JoinHandle};
 
 use types::U256;
 
+use crate::blk::BlkOptions;
+use crate::db::DbOptions;
+use crate::mempool::{Mempool, MempoolOptions};
 use crate::rpc::{RpcClient, RpcOptions};
+use crate::source::BlockSource;
+use crate::zmq::ZmqOptions;
 
+pub mod blk;
+pub mod block;
+pub mod checkpoint;
+pub mod compress;
 pub mod db;
+pub mod export;
+#[cfg(test)]
+mod fixtures;
+pub mod mempool;
+pub mod muhash;
+pub mod p2p;
+pub mod rest;
 pub mod rpc;
+pub mod snapshot;
+pub mod source;
 pub mod types;
+pub mod zmq;
 
 #[must_use]
 pub struct Index {
     pub db: Arc<db::Db>,
-    rpc_client: Arc<RpcClient>,
+    pub mempool: Arc<Mempool>,
+    source: Arc<dyn BlockSource>,
+    options: IndexOptions,
+    /// Set when indexing stopped on an unrecoverable error with `Recovery::Halt`.
+    halted: Arc<AtomicBool>,
+    /// Permits of the queries running on the database, see `query`.
+    queries: Arc<Semaphore>,
+}
+
+pub struct IndexOptions {
+    /// Number of blocks fetched concurrently ahead of the block being connected.
+    pub prefetch: usize,
+    /// Optional ZMQ subscription to wake the index as soon as a new block is published,
+    /// polling is still used as the fallback.
+    pub zmq: Option<ZmqOptions>,
+    /// Optional (read-only) bitcoind `blocks/` directory to connect blocks from `blk*.dat` files
+    /// during initial sync, before switching to the block source once the files are exhausted.
+    pub blk: Option<BlkOptions>,
+    /// Optional mempool overlay of unconfirmed transactions, requires bitcoind RPC.
+    pub mempool: Option<MempoolOptions>,
+    /// Pruning of `Block`/`BlockUndo` entries, `None` keeps them for the whole chain.
+    pub prune: Option<PruneOptions>,
+    /// What to do when a fork can't be disconnected as its undo data was pruned.
+    pub recovery: Recovery,
+    /// Size in bytes of the coin cache blocks are connected on during initial sync,
+    /// it is flushed when full and for every block once synced.
+    pub cache_size: usize,
+    /// Optional read-only secondary instance of a database indexed by another process,
+    /// `start` then only follows its writes.
+    pub secondary: Option<SecondaryOptions>,
+    /// RocksDB tuning of the database.
+    pub db: DbOptions,
+    /// Number of queries running on the database at once, each on a blocking thread.
+    pub query_concurrency: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SecondaryOptions {
+    /// Directory of the secondary instance's own logs, not the database directory.
+    pub path: PathBuf,
+    /// Catch up with the primary instance every `interval`.
+    pub interval: Duration,
+}
+
+/// Recovery from a fork deeper than the retained undo data (see `PruneOptions::depth`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Recovery {
+    /// Clear the database and reindex from genesis.
+    #[default]
+    Reindex,
+    /// Stop indexing and report not ready, leaving the database untouched for an operator.
+    Halt,
+}
+
+impl FromStr for Recovery {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reindex" => Ok(Recovery::Reindex),
+            "halt" => Ok(Recovery::Halt),
+            _ => Err(format!("Unknown recovery: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PruneOptions {
+    /// Number of most recent blocks to keep, which is also the deepest reorg that can be handled.
+    pub depth: u64,
+    /// Prune every `interval` blocks.
+    pub interval: u64,
+    /// Compact the pruned range in the background after pruning, to reclaim its space sooner.
+    pub compact: bool,
+}
+
+impl Default for PruneOptions {
+    fn default() -> Self {
+        Self {
+            depth: 10_000,
+            interval: 10_000,
+            compact: false,
+        }
+    }
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        Self {
+            prefetch: 16,
+            zmq: None,
+            blk: None,
+            mempool: None,
+            prune: Some(PruneOptions::default()),
+            recovery: Recovery::default(),
+            cache_size: 450 << 20,
+            secondary: None,
+            db: DbOptions::default(),
+            query_concurrency: 16,
+        }
+    }
 }
 
 pub struct IndexStatus {
     pub initial_indexing: bool,
+    /// Indexing stopped on an unrecoverable error, see `Recovery::Halt`.
+    pub halted: bool,
 }
 
 struct Progress {
@@ -61,39 +187,207 @@ impl fmt::Debug for Progress {
     }
 }
 
+type Fetch = JoinHandle<Result<Box<block::Block>, source::Error>>;
+
+/// Bounded prefetch of blocks by height, the fetches run concurrently but are always
+/// handed out in height order so that blocks can be connected strictly one after another.
+struct Pipeline {
+    size: usize,
+    /// Next height to schedule a fetch for.
+    height: u64,
+    /// Last known height of the chain, fetches are never scheduled past it.
+    tip: u64,
+    fetches: VecDeque<Fetch>,
+}
+
+impl Pipeline {
+    fn new(size: usize) -> Self {
+        Self {
+            size: size.max(1),
+            height: 0,
+            tip: 0,
+            fetches: VecDeque::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.fetches.is_empty()
+    }
+
+    /// Schedule fetches until `size` blocks are in-flight or the known tip is reached.
+    fn fill(&mut self, source: &Arc<dyn BlockSource>) {
+        while self.fetches.len() < self.size && self.height <= self.tip {
+            let height = self.height;
+            let source = source.clone();
+            self.fetches.push_back(spawn(async move {
+                let hash = source.get_blockhash(height).await?;
+                Ok(Box::new(source.get_block(hash, height).await?))
+            }));
+            self.height += 1;
+        }
+    }
+
+    async fn next(&mut self) -> Option<Result<Box<block::Block>, source::Error>> {
+        let fetch = self.fetches.pop_front()?;
+        Some(fetch.await.expect("Failed to join block fetch"))
+    }
+
+    /// Abort all in-flight fetches and restart the pipeline from `height`.
+    fn reset(&mut self, height: u64) {
+        for fetch in self.fetches.drain(..) {
+            fetch.abort();
+        }
+        self.height = height;
+    }
+}
+
+/// Run `f` on a blocking thread, RocksDB calls would otherwise hold up the async workers.
+/// A panic of `f` is resumed on the caller.
+async fn blocking<T, F>(db: &Arc<db::Db>, f: F) -> T
+where
+    F: FnOnce(&db::Db) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let db = db.clone();
+    spawn_blocking(move || f(&db))
+        .await
+        .unwrap_or_else(|error| panic::resume_unwind(error.into_panic()))
+}
+
+/// Run `f` on a blocking thread with a permit of `queries`, see `Index::query`.
+async fn query<T, F>(queries: &Semaphore, db: &Arc<db::Db>, f: F) -> T
+where
+    F: FnOnce(&db::Db) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let _permit = queries.acquire().await.expect("Queries closed");
+    blocking(db, f).await
+}
+
+/// Every `interval` blocks, we prune the blocks prior to the last `depth` blocks.
+/// At least the tip is always kept as it is where the index resumes from.
+/// Depth is counted from the persisted tip, blocks still in the cache may not be written yet.
+/// `pruned_at` is the height of `next` when last pruned, the first call always prunes.
+/// The compaction runs on a blocking thread, skipped while the previous one is still running.
+async fn prune(
+    db: &Arc<db::Db>,
+    next: &Progress,
+    options: Option<PruneOptions>,
+    pruned_at: &mut Option<u64>,
+    compaction: &mut Option<JoinHandle<()>>,
+) {
+    let Some(PruneOptions {
+        depth,
+        interval,
+        compact,
+    }) = options
+    else {
+        return;
+    };
+    let depth = depth.max(1);
+    let due = match *pruned_at {
+        // Polled again and again while idle at the tip
+        Some(height) => next.height >= height + interval.max(1),
+        None => true,
+    };
+    if due {
+        *pruned_at = Some(next.height);
+        let pruned = blocking(db, move |db| {
+            let height = db.peek()?.height.checked_sub(depth)?;
+            db.prune_until(height);
+            Some(height)
+        });
+        let Some(height) = pruned.await else {
+            return;
+        };
+
+        let running = matches!(compaction, Some(handle) if !handle.is_finished());
+        if compact && !running {
+            let db = db.clone();
+            *compaction = Some(spawn_blocking(move || db.compact_until(height)));
+        }
+    }
+}
+
+/// Record the genesis block hash of `source` as the chain of `db`,
+/// `false` if the database was indexed from another chain.
+async fn check_chain(db: &Arc<db::Db>, source: &dyn BlockSource) -> Result<bool, source::Error> {
+    let genesis = source.get_blockhash(0).await?;
+    match blocking(db, |db| db.chain()).await {
+        Some(chain) => Ok(chain == genesis),
+        None => {
+            blocking(db, move |db| db.set_chain(genesis)).await;
+            Ok(true)
+        }
+    }
+}
+
+/// Write the blocks connected on `cache`, the database is at the last connected block after.
+async fn flush(db: &Arc<db::Db>, mut cache: db::Cache) -> db::Cache {
+    if cache.is_empty() {
+        return cache;
+    }
+    tracing::info!("Flushing: {} blocks", cache.len());
+    blocking(db, move |db| {
+        db.flush(&mut cache);
+        cache
+    })
+    .await
+}
+
 impl Index {
-    pub fn open(path: &str, rpc: RpcOptions) -> Index {
-        let db = db::Db::open(path);
+    pub fn open(path: &str, rpc: RpcOptions, options: IndexOptions) -> Index {
+        Self::with_source(path, Arc::new(RpcClient::new(rpc)), options)
+    }
+
+    /// Index following the best chain of `source`.
+    pub fn with_source(path: &str, source: Arc<dyn BlockSource>, options: IndexOptions) -> Index {
+        let db = match &options.secondary {
+            Some(secondary) => {
+                db::Db::open_secondary(path, secondary.path.to_str().unwrap(), &options.db)
+            }
+            None => db::Db::with_options(path, &options.db),
+        };
 
         Self {
             db: Arc::new(db),
-            rpc_client: Arc::new(RpcClient::new(rpc)),
+            mempool: Arc::new(Mempool::new()),
+            source,
+            queries: Arc::new(Semaphore::new(options.query_concurrency.max(1))),
+            options,
+            halted: Arc::new(AtomicBool::new(false)),
         }
     }
 
     pub fn start(&self) -> IndexHandle {
         enum Synced {
-            Connected(Box<rpc::Block>),
+            Connected(Box<block::Block>),
             Forked,
-            Errored(rpc::Error),
+            Tip,
+            Errored(source::Error),
         }
 
-        async fn connect(next: &Progress, rpc_client: &RpcClient) -> Synced {
-            let next_hash = match rpc_client.get_blockhash(&next.height).await {
-                Ok(hash) => hash,
-                Err(error) => return Synced::Errored(error),
-            };
+        async fn connect(
+            next: &Progress,
+            pipeline: &mut Pipeline,
+            source: &Arc<dyn BlockSource>,
+        ) -> Synced {
+            if pipeline.is_empty() {
+                pipeline.reset(next.height);
+                pipeline.tip = match source.get_block_count().await {
+                    Ok(count) => count,
+                    Err(error) => return Synced::Errored(error),
+                };
+            }
 
-            let next_block = match rpc_client.get_block(&next_hash).await {
-                Ok(block) => block,
-                Err(error) => return Synced::Errored(error),
+            pipeline.fill(source);
+            let next_block = match pipeline.next().await {
+                Some(Ok(block)) => block,
+                Some(Err(error)) => return Synced::Errored(error),
+                None => return Synced::Tip,
             };
 
-            if let Some(ref parent_hash) = next_block
-                .previousblockhash
-                .as_ref()
-                .map(|hash| U256::from_hex(hash))
-            {
+            if let Some(ref parent_hash) = next_block.prev_hash {
                 if parent_hash == &next.prev_hash {
                     return Synced::Connected(next_block);
                 }
@@ -108,53 +402,163 @@ impl Index {
             }
         }
 
+        if let Some(options) = self.options.secondary.clone() {
+            return self.follow(options);
+        }
+
         let db = self.db.clone();
-        let rpc_client = self.rpc_client.clone();
+        let source = self.source.clone();
+        let mut pipeline = Pipeline::new(self.options.prefetch);
+        let blk_options = self.options.blk.clone();
+        let prune_options = self.options.prune;
+        let mut pruned_at = None;
+        let mut compaction = None;
+        let recovery = self.options.recovery;
+        let mut cache = db::Cache::new(self.options.cache_size);
+        let halted = self.halted.clone();
         let (stop_tx, mut stop_rx) = watch::channel(());
 
+        let notify = Arc::new(Notify::new());
+        if let Some(options) = self.options.zmq.clone() {
+            zmq::spawn_notifier(options, notify.clone(), stop_rx.clone());
+        }
+        if let Some(options) = self.options.mempool.clone() {
+            let queries = self.queries.clone();
+            mempool::spawn_sync(
+                self.mempool.clone(),
+                db.clone(),
+                queries,
+                options,
+                stop_rx.clone(),
+            );
+        }
+
         spawn(async move {
-            let mut next: Progress = db
-                .peek()
-                .as_ref()
-                .map(Progress::for_next)
-                .unwrap_or_else(Progress::genesis);
+            let mut next: Progress = match blocking(&db, |db| db.peek()).await {
+                Some(tip) => Progress::for_next(&tip),
+                None => {
+                    // Leftovers of an interrupted reindex
+                    blocking(&db, |db| db.clear()).await;
+                    Progress::genesis()
+                }
+            };
 
             tracing::info!("Started: {:?}", &next);
 
+            // Bootstrap sources are used for initial sync only, they are never followed into
+            // a fork, the next source takes over as soon as they are exhausted or no longer connect.
+            // The last source is followed indefinitely.
+            let mut sources: VecDeque<Arc<dyn BlockSource>> = VecDeque::from([source]);
+            if let Some(options) = blk_options {
+                match spawn_blocking(move || blk::BlkFiles::open(&options))
+                    .await
+                    .expect("Failed to join blk files open")
+                {
+                    Ok(files) => sources.push_front(Arc::new(files)),
+                    Err(error) => tracing::info!("Errored: {:?}, blk files: {:?}", &next, error),
+                }
+            }
+
             let mut sleep_until = SystemTime::now();
+            let mut chain_checked = false;
             while !stop_rx.has_changed().unwrap() {
                 if SystemTime::now() < sleep_until {
-                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+                        _ = notify.notified() => sleep_until = SystemTime::now(),
+                    }
                     continue;
                 }
 
-                // Every 10,000 blocks, we prune the blocks prior to the last 10,000 blocks
-                if next.height % 10_000 == 0 && next.height > 10_000 {
-                    db.prune_until(next.height - 10_000);
+                if !chain_checked {
+                    match check_chain(&db, sources.back().unwrap().as_ref()).await {
+                        Ok(true) => chain_checked = true,
+                        Ok(false) => {
+                            let chain = blocking(&db, |db| db.chain()).await;
+                            tracing::error!("Database is of another chain: {:?}", chain);
+                            halted.store(true, Ordering::SeqCst);
+                            break;
+                        }
+                        Err(error) => {
+                            tracing::info!("Errored: {:?}, error: {:?}", &next, error);
+                            sleep_until = SystemTime::now() + Duration::from_secs(5);
+                            continue;
+                        }
+                    }
                 }
 
-                match connect(&next, &rpc_client).await {
-                    Synced::Connected(rpc_block) => {
-                        let hash = U256::from_hex(&rpc_block.hash);
-                        db.push(*rpc_block);
+                prune(&db, &next, prune_options, &mut pruned_at, &mut compaction).await;
+
+                let synced = connect(&next, &mut pipeline, &sources[0]).await;
+                if sources.len() > 1 && !matches!(synced, Synced::Connected(_)) {
+                    sources.pop_front();
+                    pipeline.reset(next.height);
+                    tracing::info!("Switched source: {:?}", &next);
+                    continue;
+                }
+
+                match synced {
+                    Synced::Connected(block) => {
+                        let hash = block.hash;
+                        cache = blocking(&db, move |db| {
+                            db.connect(&mut cache, *block);
+                            cache
+                        })
+                        .await;
                         tracing::info!("Connected: {:?}", &next);
                         next = Progress {
                             height: next.height + 1,
                             prev_hash: hash,
                         };
+                        if cache.is_full() || next.height > pipeline.tip {
+                            cache = flush(&db, cache).await;
+                        }
                     }
                     Synced::Forked => {
-                        let popped = db.pop();
-                        next = Progress::for_fork(&popped);
-                        tracing::info!("Forked: {:?}", &next);
+                        cache = flush(&db, cache).await;
+                        match blocking(&db, |db| db.pop()).await {
+                            Ok(popped) => {
+                                next = Progress::for_fork(&popped);
+                                pipeline.reset(next.height);
+                                tracing::info!("Forked: {:?}", &next);
+                            }
+                            Err(error) => {
+                                tracing::error!(
+                                    "Fork unrecoverable: {:?}, error: {:?}",
+                                    &next,
+                                    error
+                                );
+                                match recovery {
+                                    Recovery::Reindex => {
+                                        blocking(&db, |db| db.clear()).await;
+                                        next = Progress::genesis();
+                                        pipeline.reset(next.height);
+                                        tracing::info!("Reindexing: {:?}", &next);
+                                    }
+                                    Recovery::Halt => {
+                                        halted.store(true, Ordering::SeqCst);
+                                        tracing::info!("Halted: {:?}", &next);
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    Synced::Tip => {
+                        cache = flush(&db, cache).await;
+                        sleep_until = SystemTime::now() + Duration::from_secs(1);
                     }
                     Synced::Errored(error) => {
+                        cache = flush(&db, cache).await;
                         tracing::info!("Errored: {:?}, error: {:?}", &next, error);
+                        pipeline.reset(next.height);
                         sleep_until = SystemTime::now() + Duration::from_secs(5);
                     }
                 }
             }
 
+            flush(&db, cache).await;
+            pipeline.reset(next.height);
             stop_rx.changed().await.unwrap();
             tracing::info!("Stopped index");
         });
@@ -162,14 +566,97 @@ impl Index {
         IndexHandle(Arc::new(stop_tx))
     }
 
-    pub async fn status(&self) -> Result<IndexStatus, rpc::Error> {
-        let height = self.rpc_client.get_block_count().await?;
-        match self.db.peek() {
+    /// Catch up with the primary instance every `interval` until stopped, nothing is indexed.
+    fn follow(&self, options: SecondaryOptions) -> IndexHandle {
+        let db = self.db.clone();
+        let (stop_tx, mut stop_rx) = watch::channel(());
+        if let Some(options) = self.options.mempool.clone() {
+            let queries = self.queries.clone();
+            mempool::spawn_sync(
+                self.mempool.clone(),
+                db.clone(),
+                queries,
+                options,
+                stop_rx.clone(),
+            );
+        }
+
+        spawn(async move {
+            let mut tip = blocking(&db, |db| db.peek())
+                .await
+                .map(|block| block.height);
+            tracing::info!("Following: {:?}", tip);
+
+            let mut sleep_until = SystemTime::now();
+            while !stop_rx.has_changed().unwrap() {
+                if SystemTime::now() < sleep_until {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+
+                match blocking(&db, |db| db.try_catch_up().map(|_| db.peek())).await {
+                    Ok(block) => {
+                        let height = block.map(|block| block.height);
+                        if height != tip {
+                            tip = height;
+                            tracing::info!("Caught up: {:?}", tip);
+                        }
+                    }
+                    Err(error) => tracing::info!("Errored: {:?}, error: {:?}", tip, error),
+                }
+                sleep_until = SystemTime::now() + options.interval;
+            }
+
+            stop_rx.changed().await.unwrap();
+            tracing::info!("Stopped following");
+        });
+
+        IndexHandle(Arc::new(stop_tx))
+    }
+
+    /// Bootstrap an empty index from a `dumptxoutset` snapshot based at `height`,
+    /// `start` then continues from the snapshot's base block instead of genesis.
+    /// Address history before the snapshot is unknown: `ScriptInfo` only counts its UTXOs.
+    pub async fn import(&self, path: PathBuf, height: u64) -> Result<(), snapshot::Error> {
+        let reader = spawn_blocking(move || snapshot::SnapshotReader::open(path))
+            .await
+            .expect("Failed to join snapshot open")?;
+        let base = snapshot::base(self.source.as_ref(), reader.metadata(), height).await?;
+        tracing::info!(
+            "Loading snapshot: ({}, {}), coins: {}",
+            base.height,
+            base.hash,
+            reader.metadata().coins
+        );
+
+        let db = self.db.clone();
+        spawn_blocking(move || snapshot::load(&db, reader, base))
+            .await
+            .expect("Failed to join snapshot load")
+    }
+
+    /// Run `f` on the database on a blocking thread, at most `IndexOptions::query_concurrency`
+    /// at once, queries neither hold up the async workers nor pile up on the blocking threads.
+    /// The mempool sync shares the same permits.
+    pub async fn query<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&db::Db) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        query(&self.queries, &self.db, f).await
+    }
+
+    pub async fn status(&self) -> Result<IndexStatus, source::Error> {
+        let height = self.source.get_block_count().await?;
+        let halted = self.halted.load(Ordering::SeqCst);
+        match self.query(|db| db.peek()).await {
             None => Ok(IndexStatus {
                 initial_indexing: true,
+                halted,
             }),
             Some(block) => Ok(IndexStatus {
                 initial_indexing: height > block.height + 100,
+                halted,
             }),
         }
     }
@@ -199,8 +686,250 @@ mod tests {
 
     use testcontainers_bitcoind::{Bitcoind, Sync};
 
+    use crate::fixtures;
+    use crate::rpc::BlockFormat;
+
     use super::*;
 
+    async fn settle(pipeline: &Pipeline) {
+        while !pipeline.fetches.iter().all(|fetch| fetch.is_finished()) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn pipeline_order() {
+        let chain = fixtures::regtest_chain(20);
+        let source: Arc<dyn BlockSource> = Arc::new(fixtures::MockSource::new(chain.clone()));
+        let mut pipeline = Pipeline::new(4);
+        pipeline.tip = 19;
+
+        for expected in chain.iter() {
+            pipeline.fill(&source);
+            let block = pipeline.next().await.unwrap().unwrap();
+            assert_eq!(block.hash, block::to_u256(expected.block_hash()));
+        }
+        // Nothing is scheduled past the tip
+        pipeline.fill(&source);
+        assert!(pipeline.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn pipeline_limit() {
+        let source: Arc<dyn BlockSource> =
+            Arc::new(fixtures::MockSource::new(fixtures::regtest_chain(20)));
+        let mut pipeline = Pipeline::new(4);
+        pipeline.tip = 19;
+
+        pipeline.fill(&source);
+        assert_eq!(pipeline.fetches.len(), 4);
+        assert_eq!(pipeline.height, 4);
+        pipeline.fill(&source);
+        assert_eq!(pipeline.fetches.len(), 4);
+
+        pipeline.next().await.unwrap().unwrap();
+        pipeline.fill(&source);
+        assert_eq!(pipeline.fetches.len(), 4);
+        assert_eq!(pipeline.height, 5);
+
+        // Bounded by the known tip too
+        pipeline.reset(18);
+        pipeline.fill(&source);
+        assert_eq!(pipeline.fetches.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn pipeline_reorg() {
+        let chain = fixtures::regtest_chain(8);
+        let mut fork = chain[..5].to_vec();
+        for height in 5..8 {
+            fork.push(fixtures::regtest_block(fork.last().unwrap(), height, 1));
+        }
+        let mock = Arc::new(fixtures::MockSource::new(chain.clone()));
+        let source: Arc<dyn BlockSource> = mock.clone();
+        let mut pipeline = Pipeline::new(8);
+        pipeline.tip = 7;
+        pipeline.fill(&source);
+        settle(&pipeline).await;
+
+        // Prefetched before the reorg, the block at 5 is of the stale chain
+        mock.set_chain(fork.clone());
+        for _ in 0..5 {
+            pipeline.next().await.unwrap().unwrap();
+        }
+        let stale = pipeline.next().await.unwrap().unwrap();
+        assert_eq!(stale.hash, block::to_u256(chain[5].block_hash()));
+
+        pipeline.reset(5);
+        assert!(pipeline.is_empty());
+        pipeline.fill(&source);
+        let block = pipeline.next().await.unwrap().unwrap();
+        assert_eq!(block.hash, block::to_u256(fork[5].block_hash()));
+        assert_eq!(block.prev_hash, Some(block::to_u256(fork[4].block_hash())));
+    }
+
+    #[tokio::test]
+    async fn index_source() -> anyhow::Result<()> {
+        let chain = fixtures::regtest_chain(20);
+        let source = Arc::new(fixtures::MockSource::new(chain.clone()));
+        let dir = tempdir()?;
+        let index = Index::with_source(
+            dir.path().to_str().unwrap(),
+            source,
+            IndexOptions::default(),
+        );
+
+        let handle = index.start();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        handle.stop();
+        handle.stopped().await;
+
+        let tip = index.db.peek().expect("No block connected");
+        assert_eq!(tip.height, 19);
+        assert_eq!(tip.hash, block::to_u256(chain[19].block_hash()));
+        assert_eq!(
+            index.db.chain(),
+            Some(block::to_u256(chain[0].block_hash()))
+        );
+        let script = fixtures::p2wpkh(7).to_bytes();
+        assert_eq!(
+            index.db.iterator_script_utxo(&script, None..None).count(),
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn index_fork() -> anyhow::Result<()> {
+        // Block 8 spends the coinbase of block 1, the fork from block 7 doesn't
+        let mut chain = fixtures::regtest_chain(8);
+        let mut block = fixtures::regtest_block(&chain[7], 8, 0);
+        block.txdata.push(fixtures::spend(
+            &chain[1].txdata[0],
+            0,
+            fixtures::p2wpkh(0xff),
+        ));
+        chain.push(block);
+        chain.push(fixtures::regtest_block(&chain[8], 9, 0));
+
+        let mut fork = chain[..8].to_vec();
+        for height in 8..12 {
+            fork.push(fixtures::regtest_block(fork.last().unwrap(), height, 1));
+        }
+
+        let source = Arc::new(fixtures::MockSource::new(chain.clone()));
+        let dir = tempdir()?;
+        let index = Index::with_source(
+            dir.path().to_str().unwrap(),
+            source.clone(),
+            IndexOptions::default(),
+        );
+
+        let handle = index.start();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(index.db.peek().expect("No block connected").height, 9);
+        source.set_chain(fork.clone());
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+        handle.stop();
+        handle.stopped().await;
+
+        let tip = index.db.peek().expect("No block connected");
+        assert_eq!(tip.height, 11);
+        assert_eq!(tip.hash, block::to_u256(fork[11].block_hash()));
+        let spent = fixtures::p2wpkh(0xff).to_bytes();
+        assert!(index.db.get_script_info(&spent).is_none());
+        let coinbase = fixtures::p2wpkh(1).to_bytes();
+        assert_eq!(
+            index.db.iterator_script_utxo(&coinbase, None..None).count(),
+            1
+        );
+        let verified = index
+            .db
+            .snapshot()
+            .verify(|violation| panic!("{}", violation));
+        assert_eq!(verified.blocks, 12);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prune_before_flush() {
+        let dir = tempdir().unwrap();
+        let db = Arc::new(db::Db::open(dir.path().to_str().unwrap()));
+        let chain = fixtures::regtest_chain(13);
+        let mut cache = db::Cache::new(usize::MAX);
+        for (height, block) in chain.iter().enumerate() {
+            db.connect(&mut cache, block::Block::from_bitcoin(height as u64, block));
+            if height == 4 {
+                db.flush(&mut cache);
+            }
+        }
+
+        // Blocks 5 to 12 are only in the cache, deeper than `depth`
+        let options = PruneOptions {
+            depth: 3,
+            interval: 1,
+            compact: false,
+        };
+        let next = Progress {
+            height: 13,
+            prev_hash: block::to_u256(chain[12].block_hash()),
+        };
+        prune(&db, &next, Some(options), &mut None, &mut None).await;
+        assert_eq!(db.peek().expect("Tip was pruned").height, 4);
+        assert!(db.get_block(0).is_none());
+        assert!(db.get_block(1).is_some());
+
+        db.flush(&mut cache);
+        assert_eq!(db.pop().unwrap().height, 12);
+    }
+
+    #[tokio::test]
+    async fn prune_idle() {
+        let dir = tempdir().unwrap();
+        let db = Arc::new(db::Db::open(dir.path().to_str().unwrap()));
+        let chain = fixtures::regtest_chain(13);
+        let mut cache = db::Cache::new(usize::MAX);
+        for (height, block) in chain.iter().enumerate().take(9) {
+            db.connect(&mut cache, block::Block::from_bitcoin(height as u64, block));
+        }
+        db.flush(&mut cache);
+
+        let options = Some(PruneOptions {
+            depth: 3,
+            interval: 4,
+            compact: true,
+        });
+        // Only the height is read
+        let progress = |height: u64| Progress {
+            height,
+            prev_hash: U256::zero(),
+        };
+        let mut pruned_at = None;
+        let mut compaction = None;
+        prune(&db, &progress(12), options, &mut pruned_at, &mut compaction).await;
+        assert!(db.get_block(4).is_none());
+        assert!(db.get_block(5).is_some());
+        compaction.take().expect("Not compacted").await.unwrap();
+
+        // Blocks written meanwhile would be pruned by another run
+        for (height, block) in chain.iter().enumerate().skip(9) {
+            db.connect(&mut cache, block::Block::from_bitcoin(height as u64, block));
+        }
+        db.flush(&mut cache);
+
+        // Idle at a multiple of the interval
+        for _ in 0..3 {
+            prune(&db, &progress(12), options, &mut pruned_at, &mut compaction).await;
+        }
+        assert!(db.get_block(5).is_some());
+        assert!(compaction.is_none());
+
+        prune(&db, &progress(16), options, &mut pruned_at, &mut compaction).await;
+        assert!(db.get_block(8).is_none());
+        assert!(db.get_block(9).is_some());
+        compaction.take().expect("Not compacted").await.unwrap();
+    }
+
     #[test]
     #[traced_test]
     fn index() -> anyhow::Result<()> {
@@ -212,14 +941,20 @@ mod tests {
                     url: rpc_url,
                     username: Some(username),
                     password: Some(password),
+                    format: BlockFormat::Verbose,
                 },
                 _ => RpcOptions {
                     url: rpc_url,
                     username: None,
                     password: None,
+                    format: BlockFormat::Verbose,
                 },
             };
-            Index::open(tempdir().unwrap().path().to_str().unwrap(), rpc_options)
+            Index::open(
+                tempdir().unwrap().path().to_str().unwrap(),
+                rpc_options,
+                IndexOptions::default(),
+            )
         };
 
         let client = bitcoind.client().unwrap();
@@ -313,4 +1048,96 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn index_formats() -> anyhow::Result<()> {
+        let bitcoind = Bitcoind::default().start().unwrap();
+        let client = bitcoind.client().unwrap();
+        client
+            .create_wallet("test", None, None, None, None)
+            .unwrap();
+        let address: Address<NetworkChecked> =
+            client.get_new_address(None, None).unwrap().assume_checked();
+        client.generate_to_address(101, &address).unwrap();
+        for _ in 0..10 {
+            let other = client.get_new_address(None, None).unwrap().assume_checked();
+            client
+                .send_to_address(
+                    &other,
+                    Amount::from_btc(1.2345).unwrap(),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            client.generate_to_address(1, &address).unwrap();
+        }
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let dirs = [tempdir()?, tempdir()?];
+        let [raw, verbose] = [
+            (&dirs[0], BlockFormat::Raw),
+            (&dirs[1], BlockFormat::Verbose),
+        ]
+        .map(|(dir, format)| {
+            let (username, password) = match bitcoind.rpc_auth() {
+                Some(bitcoincore_rpc::Auth::UserPass(username, password)) => {
+                    (Some(username), Some(password))
+                }
+                _ => (None, None),
+            };
+            let rpc_options = RpcOptions {
+                url: bitcoind.rpc_url().unwrap(),
+                username,
+                password,
+                format,
+            };
+            let index = Index::open(
+                dir.path().to_str().unwrap(),
+                rpc_options,
+                IndexOptions::default(),
+            );
+            rt.block_on(async {
+                let handle = index.start();
+                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                handle.stop();
+                handle.stopped().await;
+            });
+            index
+        });
+
+        for height in 0..=111 {
+            let (a, b) = (raw.db.get_block(height), verbose.db.get_block(height));
+            let (a, b) = (a.expect("raw block"), b.expect("verbose block"));
+            assert_eq!((a.hash, a.prev_hash), (b.hash, b.prev_hash));
+            assert!(a.muhash == b.muhash);
+        }
+        let (raw, verbose) = (raw.db.snapshot(), verbose.db.snapshot());
+        let utxos: Vec<_> = raw
+            .iterator_utxo()
+            .map(|utxo| serde_json::to_value(utxo).unwrap())
+            .collect();
+        assert_eq!(
+            utxos,
+            verbose
+                .iterator_utxo()
+                .map(|utxo| serde_json::to_value(utxo).unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert!(!utxos.is_empty());
+        assert_eq!(
+            raw.iterator_script_info()
+                .map(|info| serde_json::to_value(info).unwrap())
+                .collect::<Vec<_>>(),
+            verbose
+                .iterator_script_info()
+                .map(|info| serde_json::to_value(info).unwrap())
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,397 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::sync::{watch, Semaphore};
+use tokio::task::{spawn, JoinHandle};
+
+use crate::db::{Db, Utxo, Vout};
+use crate::rpc::{self, RpcClient};
+use crate::types::{U128Decimal, U256};
+use crate::zmq::Subscriber;
+use crate::{block, query};
+
+#[derive(Clone)]
+pub struct MempoolOptions {
+    pub rpc_client: Arc<RpcClient>,
+    /// Interval between `getrawmempool` polls,
+    /// which also reconcile transactions that were mined, replaced or evicted.
+    pub interval: Duration,
+    /// Optional `-zmqpubrawtx` endpoint to add transactions as soon as they are published.
+    pub zmq_url: Option<String>,
+}
+
+/// Unconfirmed transactions overlaid on top of the confirmed UTXO set in `Db`.
+///
+/// Only what is needed for `listunspent` and `getaddressinfo` is tracked:
+/// the outputs created by mempool transactions and the outputs they spend.
+#[derive(Default)]
+pub struct Mempool {
+    state: RwLock<State>,
+}
+
+#[derive(Default)]
+struct State {
+    txs: HashMap<U256, Entry>,
+    /// Outputs (confirmed or not) spent by a mempool transaction, to the spending txid.
+    spent: HashMap<Vout, U256>,
+    /// Mempool transactions funding or spending (confirmed outputs of) a script.
+    scripts: HashMap<Vec<u8>, HashSet<U256>>,
+}
+
+struct Entry {
+    tx: block::Tx,
+    /// Confirmed outputs spent by the transaction.
+    prevouts: Vec<Utxo>,
+    /// Spent outputs that aren't confirmed: created by another mempool transaction,
+    /// or not yet known to `Db` (e.g. the parent was just mined), these are retried on `resolve`.
+    pending: Vec<Vout>,
+}
+
+/// Output created by a mempool transaction.
+pub struct MempoolUtxo {
+    pub vout: Vout,
+    pub script: Vec<u8>,
+    pub value: U128Decimal,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.read().unwrap().txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, txid: &U256) -> bool {
+        self.state.read().unwrap().txs.contains_key(txid)
+    }
+
+    /// Reads `db`, to be run on a blocking thread like the queries of `Index::query`.
+    pub fn insert(&self, db: &Db, tx: block::Tx) {
+        if self.contains(&tx.txid) {
+            return;
+        }
+
+        // Resolved before taking the lock, readers are never blocked on the Db
+        let (prevouts, pending) = resolve(db, tx.vin.iter().filter_map(|vin| vin.prevout));
+
+        let mut state = self.state.write().unwrap();
+        let txid = tx.txid;
+        for vout in tx.vin.iter().filter_map(|vin| vin.prevout) {
+            state.spent.insert(vout, txid);
+        }
+        let scripts = tx.vout.iter().map(|vout| &vout.script);
+        for script in scripts.chain(prevouts.iter().map(|utxo| &utxo.key.script)) {
+            state
+                .scripts
+                .entry(script.clone())
+                .or_default()
+                .insert(txid);
+        }
+        state.txs.insert(
+            txid,
+            Entry {
+                tx,
+                prevouts,
+                pending,
+            },
+        );
+    }
+
+    pub fn remove(&self, txid: &U256) {
+        let mut state = self.state.write().unwrap();
+        let Some(entry) = state.txs.remove(txid) else {
+            return;
+        };
+
+        for vout in entry.tx.vin.iter().filter_map(|vin| vin.prevout) {
+            if state.spent.get(&vout) == Some(txid) {
+                state.spent.remove(&vout);
+            }
+        }
+        let scripts = entry.tx.vout.iter().map(|vout| &vout.script);
+        for script in scripts.chain(entry.prevouts.iter().map(|utxo| &utxo.key.script)) {
+            if let Some(txids) = state.scripts.get_mut(script) {
+                txids.remove(txid);
+                if txids.is_empty() {
+                    state.scripts.remove(script);
+                }
+            }
+        }
+    }
+
+    /// Retry resolving spent outputs that weren't confirmed when their transaction was inserted.
+    /// Reads `db`, see `insert`.
+    pub fn resolve(&self, db: &Db) {
+        let retry: Vec<(U256, Vec<Vout>)> = {
+            let state = self.state.read().unwrap();
+            state
+                .txs
+                .iter()
+                .filter_map(|(txid, entry)| {
+                    let pending: Vec<Vout> = entry
+                        .pending
+                        .iter()
+                        .filter(|vout| !state.txs.contains_key(&vout.txid))
+                        .copied()
+                        .collect();
+                    Some((*txid, pending)).filter(|(_, pending)| !pending.is_empty())
+                })
+                .collect()
+        };
+
+        for (txid, vouts) in retry {
+            let (prevouts, _) = resolve(db, vouts.into_iter());
+            if prevouts.is_empty() {
+                continue;
+            }
+
+            let mut state = self.state.write().unwrap();
+            for utxo in prevouts.iter() {
+                state
+                    .scripts
+                    .entry(utxo.key.script.clone())
+                    .or_default()
+                    .insert(txid);
+            }
+            if let Some(entry) = state.txs.get_mut(&txid) {
+                entry
+                    .pending
+                    .retain(|vout| prevouts.iter().all(|utxo| &utxo.key.vout != vout));
+                entry.prevouts.extend(prevouts);
+            }
+        }
+    }
+
+    pub fn txids(&self) -> Vec<U256> {
+        self.state.read().unwrap().txs.keys().copied().collect()
+    }
+
+    /// Whether the output is spent by a mempool transaction.
+    pub fn is_spent(&self, vout: &Vout) -> bool {
+        self.state.read().unwrap().spent.contains_key(vout)
+    }
+
+    /// Outputs to `script` created by mempool transactions and not spent by another one.
+    pub fn unspent(&self, script: &[u8]) -> Vec<MempoolUtxo> {
+        let state = self.state.read().unwrap();
+        let Some(txids) = state.scripts.get(script) else {
+            return Vec::new();
+        };
+
+        let mut utxos = Vec::new();
+        for txid in txids {
+            let entry = &state.txs[txid];
+            for output in entry.tx.vout.iter().filter(|vout| vout.script == script) {
+                let vout = Vout {
+                    txid: *txid,
+                    n: output.n,
+                };
+                if !state.spent.contains_key(&vout) {
+                    utxos.push(MempoolUtxo {
+                        vout,
+                        script: output.script.clone(),
+                        value: output.value,
+                    });
+                }
+            }
+        }
+        utxos
+    }
+
+    /// Confirmed outputs to `script` spent by mempool transactions.
+    pub fn spent(&self, script: &[u8]) -> Vec<Utxo> {
+        let state = self.state.read().unwrap();
+        let Some(txids) = state.scripts.get(script) else {
+            return Vec::new();
+        };
+
+        txids
+            .iter()
+            .flat_map(|txid| state.txs[txid].prevouts.iter())
+            .filter(|utxo| utxo.key.script == script)
+            .cloned()
+            .collect()
+    }
+}
+
+fn resolve(db: &Db, vouts: impl Iterator<Item = Vout>) -> (Vec<Utxo>, Vec<Vout>) {
+    let mut prevouts = Vec::new();
+    let mut pending = Vec::new();
+    for vout in vouts {
+        match db.find_utxo(&vout) {
+            Some(utxo) => prevouts.push(utxo),
+            None => pending.push(vout),
+        }
+    }
+    (prevouts, pending)
+}
+
+/// Reconcile the mempool with `getrawmempool`: drop what is gone and fetch what is new.
+async fn poll(
+    mempool: &Arc<Mempool>,
+    db: &Arc<Db>,
+    queries: &Semaphore,
+    rpc_client: &RpcClient,
+) -> Result<(), rpc::Error> {
+    let txids: HashSet<U256> = rpc_client
+        .get_raw_mempool()
+        .await?
+        .iter()
+        .map(|txid| U256::from_hex(txid))
+        .collect();
+
+    for txid in mempool.txids() {
+        if !txids.contains(&txid) {
+            mempool.remove(&txid);
+        }
+    }
+
+    for txid in txids {
+        if mempool.contains(&txid) {
+            continue;
+        }
+        match rpc_client.fetch_transaction(&txid.to_hex()).await {
+            Ok(tx) => {
+                let mempool = mempool.clone();
+                query(queries, db, move |db| mempool.insert(db, tx)).await
+            }
+            // Mined or evicted since getrawmempool, the next poll catches up
+            Err(rpc::Error::Rpc(_)) => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    let mempool = mempool.clone();
+    query(queries, db, move |db| mempool.resolve(db)).await;
+    Ok(())
+}
+
+/// Spawn the tasks keeping `mempool` in sync with bitcoind until stopped.
+/// Database reads take a permit of `queries`, see `Index::query`.
+pub fn spawn_sync(
+    mempool: Arc<Mempool>,
+    db: Arc<Db>,
+    queries: Arc<Semaphore>,
+    options: MempoolOptions,
+    mut stop_rx: watch::Receiver<()>,
+) -> JoinHandle<()> {
+    if let Some(url) = options.zmq_url.clone() {
+        let (mempool, db, queries) = (mempool.clone(), db.clone(), queries.clone());
+        spawn_rawtx(mempool, db, queries, url, stop_rx.clone());
+    }
+
+    spawn(async move {
+        loop {
+            if let Err(error) = poll(&mempool, &db, &queries, &options.rpc_client).await {
+                tracing::info!("Mempool errored: {:?}", error);
+            }
+
+            tokio::select! {
+                _ = stop_rx.changed() => return,
+                _ = tokio::time::sleep(options.interval) => {}
+            }
+        }
+    })
+}
+
+/// Insert transactions published on `-zmqpubrawtx`,
+/// transactions connected in blocks are published too and are removed on the next poll.
+fn spawn_rawtx(
+    mempool: Arc<Mempool>,
+    db: Arc<Db>,
+    queries: Arc<Semaphore>,
+    url: String,
+    mut stop_rx: watch::Receiver<()>,
+) -> JoinHandle<()> {
+    async fn subscribe(
+        url: &str,
+        mempool: &Arc<Mempool>,
+        db: &Arc<Db>,
+        queries: &Semaphore,
+    ) -> io::Result<()> {
+        let mut subscriber = Subscriber::connect(url, "rawtx").await?;
+        tracing::info!("ZMQ subscribed: {} rawtx", url);
+        loop {
+            let message = subscriber.recv().await?;
+            match message.get(1).map(|bytes| block::Tx::from_raw(bytes)) {
+                Some(Ok(tx)) => {
+                    let mempool = mempool.clone();
+                    query(queries, db, move |db| mempool.insert(db, tx)).await
+                }
+                Some(Err(error)) => tracing::info!("ZMQ rawtx errored: {:?}", error),
+                None => {}
+            }
+        }
+    }
+
+    spawn(async move {
+        loop {
+            tokio::select! {
+                _ = stop_rx.changed() => return,
+                result = subscribe(&url, &mempool, &db, &queries) => {
+                    if let Err(error) = result {
+                        tracing::info!("ZMQ errored: {}, error: {:?}", url, error);
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = stop_rx.changed() => return,
+                _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::fixtures;
+
+    use super::*;
+
+    #[test]
+    fn overlay() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path().to_str().unwrap());
+        let chain = fixtures::regtest_chain(3);
+        for (height, block) in chain.iter().enumerate() {
+            db.push(block::Block::from_bitcoin(height as u64, block));
+        }
+
+        let confirmed = fixtures::p2wpkh(1);
+        let script = fixtures::p2wpkh(0xaa);
+        let parent = fixtures::spend(&chain[1].txdata[0], 0, script.clone());
+        let child = fixtures::spend(&parent, 0, fixtures::p2wpkh(0xbb));
+
+        let mempool = Mempool::new();
+        // Out of order, the child is inserted before its parent
+        mempool.insert(&db, block::Tx::from_bitcoin(&child));
+        mempool.insert(&db, block::Tx::from_bitcoin(&parent));
+        assert_eq!(mempool.len(), 2);
+
+        let spent = mempool.spent(confirmed.as_bytes());
+        assert_eq!(spent.len(), 1);
+        assert!(mempool.is_spent(&spent[0].key.vout));
+        // Spent by the child
+        assert!(mempool.unspent(script.as_bytes()).is_empty());
+        assert_eq!(mempool.unspent(fixtures::p2wpkh(0xbb).as_bytes()).len(), 1);
+
+        mempool.remove(&block::to_u256(child.compute_txid()));
+        assert_eq!(mempool.unspent(script.as_bytes()).len(), 1);
+
+        mempool.remove(&block::to_u256(parent.compute_txid()));
+        assert!(mempool.is_empty());
+        assert!(mempool.spent(confirmed.as_bytes()).is_empty());
+        assert!(mempool.unspent(script.as_bytes()).is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+//! Rolling UTXO set hash, compatible with Bitcoin Core's `gettxoutsetinfo muhash`.
+//!
+//! Reference:
+//! https://github.com/bitcoin/bitcoin/blob/master/src/crypto/muhash.cpp
+//! https://github.com/bitcoin/bitcoin/blob/master/src/kernel/coinstats.cpp
+
+use bitcoin::hashes::{sha256, Hash};
+use num_bigint::BigUint;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::compress::{write_compact_size, MAX_SCRIPT_SIZE};
+use crate::db::Utxo;
+use crate::types::U256;
+
+const BYTE_SIZE: usize = 384;
+const BITS: u64 = 3072;
+/// The prime is `2^3072 - C`, the largest safe prime below `2^3072`.
+const C: u32 = 1103717;
+const OP_RETURN: u8 = 0x6a;
+
+static PRIME: Lazy<BigUint> = Lazy::new(|| (BigUint::from(1u8) << BITS) - C);
+static MASK: Lazy<BigUint> = Lazy::new(|| (BigUint::from(1u8) << BITS) - 1u8);
+
+/// MuHash3072 of a set: the product of its hashed elements modulo a 3072-bit prime,
+/// kept as a fraction so that both inserting and removing an element is a single multiplication.
+/// The set hash doesn't depend on the order elements were inserted or removed in.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "(Vec<u8>, Vec<u8>)", into = "(Vec<u8>, Vec<u8>)")]
+pub struct MuHash {
+    numerator: BigUint,
+    denominator: BigUint,
+}
+
+impl Default for MuHash {
+    fn default() -> Self {
+        Self {
+            numerator: BigUint::from(1u8),
+            denominator: BigUint::from(1u8),
+        }
+    }
+}
+
+impl MuHash {
+    pub fn insert(&mut self, data: &[u8]) {
+        self.numerator = multiply(&self.numerator, &to_num3072(data));
+    }
+
+    pub fn remove(&mut self, data: &[u8]) {
+        self.denominator = multiply(&self.denominator, &to_num3072(data));
+    }
+
+    /// Insert an unspent output, unless Bitcoin Core keeps it out of its UTXO set.
+    pub fn insert_utxo(&mut self, utxo: &Utxo) {
+        if let Some(data) = serialize_utxo(utxo) {
+            self.insert(&data);
+        }
+    }
+
+    pub fn remove_utxo(&mut self, utxo: &Utxo) {
+        if let Some(data) = serialize_utxo(utxo) {
+            self.remove(&data);
+        }
+    }
+
+    /// Hash of the set, in the byte order `gettxoutsetinfo` displays it.
+    pub fn finalize(&self) -> U256 {
+        let inverse = self.denominator.modpow(&(&*PRIME - 2u8), &PRIME);
+        let mut bytes = multiply(&self.numerator, &inverse).to_bytes_le();
+        bytes.resize(BYTE_SIZE, 0);
+        let mut hash = sha256::Hash::hash(&bytes).to_byte_array();
+        hash.reverse();
+        U256::from(hash)
+    }
+}
+
+impl From<(Vec<u8>, Vec<u8>)> for MuHash {
+    fn from((numerator, denominator): (Vec<u8>, Vec<u8>)) -> Self {
+        Self {
+            numerator: BigUint::from_bytes_le(&numerator),
+            denominator: BigUint::from_bytes_le(&denominator),
+        }
+    }
+}
+
+impl From<MuHash> for (Vec<u8>, Vec<u8>) {
+    fn from(value: MuHash) -> Self {
+        (
+            value.numerator.to_bytes_le(),
+            value.denominator.to_bytes_le(),
+        )
+    }
+}
+
+/// Serialization of a coin as Core's `TxOutSer`, `None` for outputs that are never in its UTXO set:
+/// the genesis coinbase and unspendable scripts.
+fn serialize_utxo(utxo: &Utxo) -> Option<Vec<u8>> {
+    let key = &utxo.key;
+    let unspendable =
+        key.script.first() == Some(&OP_RETURN) || key.script.len() as u64 > MAX_SCRIPT_SIZE;
+    if key.height == 0 || unspendable {
+        return None;
+    }
+
+    let mut txid: [u8; 32] = key.vout.txid.into();
+    txid.reverse();
+    let value = match utxo.value.1 {
+        scale @ 0..=8 => utxo.value.0 * 10u128.pow(8 - scale as u32),
+        scale => utxo.value.0 / 10u128.pow(scale as u32 - 8),
+    };
+
+    let mut data = Vec::with_capacity(32 + 4 + 4 + 8 + 1 + key.script.len());
+    data.extend_from_slice(&txid);
+    data.extend_from_slice(&key.vout.n.to_le_bytes());
+    data.extend_from_slice(&((key.height as u32) << 1 | utxo.coinbase as u32).to_le_bytes());
+    data.extend_from_slice(&(value as i64).to_le_bytes());
+    write_compact_size(&mut data, key.script.len() as u64).unwrap();
+    data.extend_from_slice(&key.script);
+    Some(data)
+}
+
+/// Element of the group: SHA256 of the data expanded to 3072 bits with ChaCha20.
+fn to_num3072(data: &[u8]) -> BigUint {
+    let key = sha256::Hash::hash(data).to_byte_array();
+    reduce(BigUint::from_bytes_le(&chacha20(&key)))
+}
+
+fn multiply(a: &BigUint, b: &BigUint) -> BigUint {
+    reduce(a * b)
+}
+
+/// Modulo `2^3072 - C` without a division, as `x = hi * 2^3072 + lo = hi * C + lo`.
+fn reduce(mut x: BigUint) -> BigUint {
+    while x.bits() > BITS {
+        x = (&x & &*MASK) + (x >> BITS) * C;
+    }
+    if x >= *PRIME {
+        x -= &*PRIME;
+    }
+    x
+}
+
+/// ChaCha20 (RFC 8439) keystream with a zero nonce, 384 bytes from block 0.
+fn chacha20(key: &[u8; 32]) -> [u8; BYTE_SIZE] {
+    fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(16);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(12);
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(8);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(7);
+    }
+
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for (i, word) in key.chunks_exact(4).enumerate() {
+        input[4 + i] = u32::from_le_bytes(word.try_into().unwrap());
+    }
+
+    let mut out = [0u8; BYTE_SIZE];
+    for (counter, block) in out.chunks_exact_mut(64).enumerate() {
+        input[12] = counter as u32;
+        let mut state = input;
+        for _ in 0..10 {
+            quarter_round(&mut state, 0, 4, 8, 12);
+            quarter_round(&mut state, 1, 5, 9, 13);
+            quarter_round(&mut state, 2, 6, 10, 14);
+            quarter_round(&mut state, 3, 7, 11, 15);
+            quarter_round(&mut state, 0, 5, 10, 15);
+            quarter_round(&mut state, 1, 6, 11, 12);
+            quarter_round(&mut state, 2, 7, 8, 13);
+            quarter_round(&mut state, 3, 4, 9, 14);
+        }
+        for (i, bytes) in block.chunks_exact_mut(4).enumerate() {
+            bytes.copy_from_slice(&state[i].wrapping_add(input[i]).to_le_bytes());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chacha20_keystream() {
+        // RFC 8439 A.1, test vector #1
+        let keystream = chacha20(&[0; 32]);
+        assert_eq!(
+            hex::encode(&keystream[..64]),
+            "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+             da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586"
+        );
+    }
+
+    #[test]
+    fn muhash() {
+        // Bitcoin Core's crypto_tests/muhash_tests
+        let element = |i: u8| {
+            let mut data = [0u8; 32];
+            data[0] = i;
+            data
+        };
+        let mut muhash = MuHash::default();
+        muhash.insert(&element(0));
+        muhash.insert(&element(1));
+        muhash.remove(&element(2));
+        assert_eq!(
+            muhash.finalize().to_hex(),
+            "10d312b100cbd32ada024a6646e40d3482fcff103668d2625f10002a607d5863"
+        );
+
+        // Order independent, and removing an element undoes inserting it
+        let mut other = MuHash::default();
+        other.remove(&element(2));
+        other.insert(&element(3));
+        other.insert(&element(1));
+        other.insert(&element(0));
+        other.remove(&element(3));
+        assert!(other.finalize() == muhash.finalize());
+
+        let decoded: MuHash = <(Vec<u8>, Vec<u8>)>::from(muhash.clone()).into();
+        assert!(decoded == muhash);
+    }
+}
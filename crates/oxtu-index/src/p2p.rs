@@ -0,0 +1,448 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::{deserialize, encode, serialize, Params};
+use bitcoin::hashes::Hash;
+use bitcoin::p2p::address::Address;
+use bitcoin::p2p::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::p2p::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::p2p::message_network::VersionMessage;
+use bitcoin::p2p::{Magic, ServiceFlags};
+use bitcoin::{BlockHash, Network};
+use rand::prelude::random;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::block::{from_u256, to_u256, Block};
+use crate::source::{self, BlockSource, BoxFuture};
+use crate::types::U256;
+
+/// Protocol version announced to the peer, 70016 is the first to support `wtxidrelay`
+/// and is what recent Bitcoin Core versions expect.
+const PROTOCOL_VERSION: u32 = 70016;
+/// Messages are ~4MB at most (a block), anything larger is a misbehaving peer.
+const MAX_PAYLOAD_SIZE: u32 = 32 * 1024 * 1024;
+/// Maximum number of headers returned in a single `headers` message.
+const MAX_HEADERS: usize = 2000;
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct P2pOptions {
+    /// Address of the full node, e.g. `127.0.0.1:8333`
+    pub addr: String,
+    pub network: Network,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Decode(encode::Error),
+    /// The peer sent something that doesn't follow the protocol (or the chain).
+    Protocol(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<encode::Error> for Error {
+    fn from(err: encode::Error) -> Error {
+        Error::Decode(err)
+    }
+}
+
+/// Single connection to a full node speaking the Bitcoin P2P protocol.
+///
+/// Reference:
+/// https://developer.bitcoin.org/reference/p2p_networking.html
+pub struct Peer {
+    stream: TcpStream,
+    magic: Magic,
+}
+
+impl Peer {
+    /// Connect and complete the `version`/`verack` handshake.
+    pub async fn connect(addr: &str, network: Network) -> Result<Peer, Error> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut peer = Peer {
+            magic: network.magic(),
+            stream,
+        };
+
+        let version = version_message(peer.stream.peer_addr()?, peer.stream.local_addr()?);
+        peer.send(NetworkMessage::Version(version)).await?;
+
+        let (mut version, mut verack) = (false, false);
+        while !(version && verack) {
+            match peer.recv().await? {
+                NetworkMessage::Version(_) => {
+                    version = true;
+                    peer.send(NetworkMessage::Verack).await?;
+                }
+                NetworkMessage::Verack => verack = true,
+                _ => {}
+            }
+        }
+
+        Ok(peer)
+    }
+
+    pub async fn send(&mut self, message: NetworkMessage) -> Result<(), Error> {
+        let bytes = serialize(&RawNetworkMessage::new(self.magic, message));
+        self.stream.write_all(&bytes).await?;
+        Ok(())
+    }
+
+    /// Receive the next message, pings are answered here and not returned.
+    pub async fn recv(&mut self) -> Result<NetworkMessage, Error> {
+        loop {
+            let message = tokio::time::timeout(TIMEOUT, self.read())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Peer timed out"))??;
+            match message {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce)).await?,
+                message => return Ok(message),
+            }
+        }
+    }
+
+    async fn read(&mut self) -> Result<NetworkMessage, Error> {
+        // magic (4), command (12), payload size (4), checksum (4)
+        let mut bytes = vec![0u8; 24];
+        self.stream.read_exact(&mut bytes).await?;
+        let size = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
+        if size > MAX_PAYLOAD_SIZE {
+            return Err(Error::Protocol(format!("Payload too large: {}", size)));
+        }
+        bytes.resize(24 + size as usize, 0);
+        self.stream.read_exact(&mut bytes[24..]).await?;
+
+        let message: RawNetworkMessage = deserialize(&bytes)?;
+        if message.magic() != &self.magic {
+            return Err(Error::Protocol(format!("Wrong magic: {}", message.magic())));
+        }
+        Ok(message.payload().clone())
+    }
+}
+
+fn version_message(receiver: SocketAddr, sender: SocketAddr) -> VersionMessage {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    VersionMessage {
+        version: PROTOCOL_VERSION,
+        ..VersionMessage::new(
+            ServiceFlags::NONE,
+            timestamp,
+            Address::new(&receiver, ServiceFlags::NONE),
+            Address::new(&sender, ServiceFlags::NONE),
+            random(),
+            format!("/oxtu:{}/", env!("CARGO_PKG_VERSION")),
+            0,
+        )
+    }
+}
+
+/// Block source syncing from a full node over the P2P protocol, no RPC access is needed.
+///
+/// The best header chain of the peer is followed with `getheaders`,
+/// blocks are then requested by hash with `getdata`.
+/// Requests share a single connection so they are served one at a time,
+/// the connection is re-established on the next request after any error.
+pub struct PeerClient {
+    options: P2pOptions,
+    state: Mutex<State>,
+}
+
+struct State {
+    peer: Option<Peer>,
+    /// Best header chain of the peer, block hash by height.
+    headers: Vec<BlockHash>,
+}
+
+impl PeerClient {
+    pub fn new(options: P2pOptions) -> PeerClient {
+        let genesis = genesis_block(options.network).block_hash();
+        Self {
+            options,
+            state: Mutex::new(State {
+                peer: None,
+                headers: vec![genesis],
+            }),
+        }
+    }
+
+    async fn peer<'a>(&self, state: &'a mut State) -> Result<&'a mut Peer, Error> {
+        if state.peer.is_none() {
+            let peer = Peer::connect(&self.options.addr, self.options.network).await?;
+            tracing::info!("P2P connected: {}", self.options.addr);
+            state.peer = Some(peer);
+        }
+        Ok(state.peer.as_mut().unwrap())
+    }
+
+    /// Extend (or reorg) the header chain until the peer has no more headers for us.
+    /// Headers must meet their target, which can't be easier than the network's.
+    async fn sync_headers(&self, state: &mut State) -> Result<(), Error> {
+        let max_target = Params::new(self.options.network).max_attainable_target;
+        loop {
+            let locator = locator(&state.headers);
+            let peer = self.peer(state).await?;
+            peer.send(NetworkMessage::GetHeaders(GetHeadersMessage::new(
+                locator,
+                BlockHash::all_zeros(),
+            )))
+            .await?;
+            let headers = loop {
+                if let NetworkMessage::Headers(headers) = peer.recv().await? {
+                    break headers;
+                }
+            };
+
+            let Some(first) = headers.first() else {
+                return Ok(());
+            };
+            // Headers connect to one of the locator hashes, anything above it was reorged
+            let fork = state
+                .headers
+                .iter()
+                .rposition(|hash| hash == &first.prev_blockhash)
+                .ok_or_else(|| Error::Protocol("Headers don't connect".to_string()))?;
+            state.headers.truncate(fork + 1);
+            for header in &headers {
+                if &header.prev_blockhash != state.headers.last().unwrap() {
+                    return Err(Error::Protocol("Headers are not continuous".to_string()));
+                }
+                let target = header.target();
+                let hash = match header.validate_pow(target) {
+                    Ok(hash) if target <= max_target => hash,
+                    _ => {
+                        let hash = header.block_hash();
+                        return Err(Error::Protocol(format!("Invalid proof of work: {}", hash)));
+                    }
+                };
+                state.headers.push(hash);
+            }
+
+            if headers.len() < MAX_HEADERS {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn fetch_block(
+        &self,
+        state: &mut State,
+        hash: BlockHash,
+    ) -> Result<Option<bitcoin::Block>, Error> {
+        let peer = self.peer(state).await?;
+        peer.send(NetworkMessage::GetData(vec![Inventory::WitnessBlock(hash)]))
+            .await?;
+        loop {
+            match peer.recv().await? {
+                NetworkMessage::Block(block) if block.block_hash() == hash => {
+                    // The header was requested, its transactions are only known by its commitments
+                    if !block.check_merkle_root() || !block.check_witness_commitment() {
+                        return Err(Error::Protocol(format!("Invalid block: {}", hash)));
+                    }
+                    return Ok(Some(block));
+                }
+                NetworkMessage::NotFound(_) => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Block locator from the tip: the last 10 blocks then exponentially sparser down to genesis.
+fn locator(headers: &[BlockHash]) -> Vec<BlockHash> {
+    let mut locator = Vec::new();
+    let mut height = headers.len() - 1;
+    let mut step = 1;
+    loop {
+        locator.push(headers[height]);
+        if height == 0 {
+            return locator;
+        }
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        height = height.saturating_sub(step);
+    }
+}
+
+impl BlockSource for PeerClient {
+    fn get_blockhash(&self, height: u64) -> BoxFuture<'_, Result<U256, source::Error>> {
+        Box::pin(async move {
+            let state = self.state.lock().await;
+            let hash = state
+                .headers
+                .get(height as usize)
+                .ok_or(source::Error::NotFound)?;
+            Ok(to_u256(*hash))
+        })
+    }
+
+    fn get_block(&self, hash: U256, height: u64) -> BoxFuture<'_, Result<Block, source::Error>> {
+        Box::pin(async move {
+            let mut state = self.state.lock().await;
+            let result = self.fetch_block(&mut state, from_u256(hash)).await;
+            if result.is_err() {
+                state.peer = None;
+            }
+            let block = result?.ok_or(source::Error::NotFound)?;
+            Ok(Block::from_bitcoin(height, &block))
+        })
+    }
+
+    fn get_block_count(&self) -> BoxFuture<'_, Result<u64, source::Error>> {
+        Box::pin(async move {
+            let mut state = self.state.lock().await;
+            let result = self.sync_headers(&mut state).await;
+            if result.is_err() {
+                state.peer = None;
+            }
+            result?;
+            Ok(state.headers.len() as u64 - 1)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::task::spawn;
+
+    use crate::fixtures;
+
+    use super::*;
+
+    /// Stand-in for a full node serving `chain` to a single connection.
+    async fn serve(listener: TcpListener, chain: Vec<bitcoin::Block>) -> Result<(), Error> {
+        let (stream, _) = listener.accept().await?;
+        let mut peer = Peer {
+            stream,
+            magic: Network::Regtest.magic(),
+        };
+
+        loop {
+            let response = match peer.recv().await? {
+                NetworkMessage::Version(_) => {
+                    let addr = peer.stream.local_addr()?;
+                    peer.send(NetworkMessage::Version(version_message(addr, addr)))
+                        .await?;
+                    NetworkMessage::Verack
+                }
+                NetworkMessage::GetHeaders(request) => {
+                    let start = chain
+                        .iter()
+                        .rposition(|block| request.locator_hashes.contains(&block.block_hash()))
+                        .unwrap_or_default();
+                    NetworkMessage::Headers(
+                        chain[start + 1..]
+                            .iter()
+                            .take(MAX_HEADERS)
+                            .map(|block| block.header)
+                            .collect(),
+                    )
+                }
+                NetworkMessage::GetData(inventory) => match &inventory[..] {
+                    [Inventory::WitnessBlock(hash)] => {
+                        match chain.iter().find(|block| &block.block_hash() == hash) {
+                            Some(block) => NetworkMessage::Block(block.clone()),
+                            None => NetworkMessage::NotFound(inventory),
+                        }
+                    }
+                    _ => NetworkMessage::NotFound(inventory),
+                },
+                _ => continue,
+            };
+            peer.send(NetworkMessage::Ping(1)).await?;
+            peer.send(response).await?;
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_from_peer() -> anyhow::Result<()> {
+        let chain = fixtures::regtest_chain(30);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = PeerClient::new(P2pOptions {
+            addr: listener.local_addr()?.to_string(),
+            network: Network::Regtest,
+        });
+        let server = spawn(serve(listener, chain.clone()));
+
+        assert_eq!(client.get_block_count().await.unwrap(), 29);
+        for (height, expected) in chain.iter().enumerate() {
+            let hash = BlockSource::get_blockhash(&client, height as u64)
+                .await
+                .unwrap();
+            assert_eq!(hash, to_u256(expected.block_hash()));
+            let block = client.get_block(hash, height as u64).await.unwrap();
+            assert_eq!(block.hash, hash);
+            assert_eq!(block.tx[0].txid, to_u256(expected.txdata[0].compute_txid()));
+        }
+        assert!(matches!(
+            client.get_blockhash(30).await,
+            Err(source::Error::NotFound)
+        ));
+
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_invalid() -> anyhow::Result<()> {
+        async fn connect(chain: Vec<bitcoin::Block>) -> anyhow::Result<PeerClient> {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let client = PeerClient::new(P2pOptions {
+                addr: listener.local_addr()?.to_string(),
+                network: Network::Regtest,
+            });
+            spawn(serve(listener, chain));
+            Ok(client)
+        }
+
+        // Transactions that aren't the ones committed to by the header
+        let mut chain = fixtures::regtest_chain(6);
+        let spend = fixtures::spend(&chain[1].txdata[0], 0, fixtures::p2wpkh(9));
+        chain[5].txdata.push(spend);
+        let client = connect(chain.clone()).await?;
+        assert_eq!(client.get_block_count().await.unwrap(), 5);
+        let hash = to_u256(chain[5].block_hash());
+        assert!(matches!(
+            client.get_block(hash, 5).await,
+            Err(source::Error::P2p(Error::Protocol(_)))
+        ));
+
+        // Header below the regtest difficulty, not mined for its own target
+        let mut block = fixtures::regtest_block(&chain[4], 5, 1);
+        block.header.bits = bitcoin::CompactTarget::from_consensus(0x1d00ffff);
+        chain[5] = block;
+        let client = connect(chain).await?;
+        assert!(matches!(
+            client.get_block_count().await,
+            Err(source::Error::P2p(Error::Protocol(_)))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn locator_is_sparse() {
+        let headers: Vec<BlockHash> = fixtures::regtest_chain(100)
+            .iter()
+            .map(|block| block.block_hash())
+            .collect();
+        let locator = locator(&headers);
+        assert_eq!(locator[0], headers[99]);
+        assert_eq!(locator[9], headers[90]);
+        assert_eq!(locator[10], headers[88]);
+        assert_eq!(locator.last(), Some(&headers[0]));
+    }
+}
@@ -0,0 +1,190 @@
+use bitcoin::consensus::encode;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+
+use crate::block::Block;
+use crate::source::{self, BlockSource, BoxFuture};
+use crate::types::U256;
+
+/// Client for the unauthenticated REST interface of Bitcoin Core (`-rest`).
+///
+/// Blocks are requested in their binary consensus encoding,
+/// so unlike `RpcClient` only chains that follow Bitcoin consensus encoding are supported.
+///
+/// Reference:
+/// https://github.com/bitcoin/bitcoin/blob/master/doc/REST-interface.md
+pub struct RestClient {
+    client: Client,
+    url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChainInfo {
+    blocks: u64,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Request(reqwest::Error),
+    Status(StatusCode),
+    Decode(encode::Error),
+    InvalidHash(usize),
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Error {
+        Error::Request(err)
+    }
+}
+
+impl From<encode::Error> for Error {
+    fn from(err: encode::Error) -> Error {
+        Error::Decode(err)
+    }
+}
+
+impl RestClient {
+    /// `url` of bitcoind without the `/rest` path, e.g. `http://127.0.0.1:8332`
+    pub fn new(url: String) -> RestClient {
+        Self {
+            client: Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    async fn get(&self, path: &str) -> Result<reqwest::Response, Error> {
+        let resp = self
+            .client
+            .get(format!("{}/rest/{}", self.url, path))
+            .send()
+            .await?;
+        match resp.status() {
+            StatusCode::OK => Ok(resp),
+            status => Err(Error::Status(status)),
+        }
+    }
+
+    pub async fn get_blockhash(&self, height: u64) -> Result<U256, Error> {
+        let bytes = self
+            .get(&format!("blockhashbyheight/{}.bin", height))
+            .await?
+            .bytes()
+            .await?;
+        // Binary hashes are in internal byte order, reversed from the displayed order we store
+        let mut hash: [u8; 32] = bytes[..]
+            .try_into()
+            .map_err(|_| Error::InvalidHash(bytes.len()))?;
+        hash.reverse();
+        Ok(U256::from(hash))
+    }
+
+    pub async fn get_block(&self, hash: &U256, height: u64) -> Result<Block, Error> {
+        let bytes = self
+            .get(&format!("block/{}.bin", hash.to_hex()))
+            .await?
+            .bytes()
+            .await?;
+        Ok(Block::from_raw(height, &bytes)?)
+    }
+
+    pub async fn get_block_count(&self) -> Result<u64, Error> {
+        let info: ChainInfo = self.get("chaininfo.json").await?.json().await?;
+        Ok(info.blocks)
+    }
+}
+
+impl BlockSource for RestClient {
+    fn get_blockhash(&self, height: u64) -> BoxFuture<'_, Result<U256, source::Error>> {
+        Box::pin(async move { Ok(RestClient::get_blockhash(self, height).await?) })
+    }
+
+    fn get_block(&self, hash: U256, height: u64) -> BoxFuture<'_, Result<Block, source::Error>> {
+        Box::pin(async move { Ok(RestClient::get_block(self, &hash, height).await?) })
+    }
+
+    fn get_block_count(&self) -> BoxFuture<'_, Result<u64, source::Error>> {
+        Box::pin(async move { Ok(RestClient::get_block_count(self).await?) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::consensus::serialize;
+    use bitcoin::hashes::Hash;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::task::spawn;
+
+    use crate::block::to_u256;
+    use crate::fixtures;
+
+    use super::*;
+
+    /// Stand-in for bitcoind's REST interface serving `chain`, one request per connection.
+    async fn serve(listener: TcpListener, chain: Vec<bitcoin::Block>) -> std::io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = BufReader::new(stream);
+            let mut request = String::new();
+            stream.read_line(&mut request).await?;
+            let path = request.split(' ').nth(1).unwrap_or_default().to_string();
+            // Drain the headers
+            let mut line = String::new();
+            while stream.read_line(&mut line).await? > 2 {
+                line.clear();
+            }
+
+            let body = if path == "/rest/chaininfo.json" {
+                Some(format!("{{\"blocks\":{}}}", chain.len() - 1).into_bytes())
+            } else if let Some(height) = path.strip_prefix("/rest/blockhashbyheight/") {
+                let height: usize = height.trim_end_matches(".bin").parse().unwrap();
+                chain
+                    .get(height)
+                    .map(|block| block.block_hash().to_byte_array().to_vec())
+            } else if let Some(hash) = path.strip_prefix("/rest/block/") {
+                let hash = hash.trim_end_matches(".bin");
+                chain
+                    .iter()
+                    .find(|block| block.block_hash().to_string() == hash)
+                    .map(serialize)
+            } else {
+                None
+            };
+
+            let head = match &body {
+                Some(body) => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n", body.len()),
+                None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n".to_string(),
+            };
+            let stream = stream.get_mut();
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(b"Connection: close\r\n\r\n").await?;
+            stream.write_all(&body.unwrap_or_default()).await?;
+            stream.shutdown().await?;
+        }
+    }
+
+    #[tokio::test]
+    async fn get_block_by_height() -> anyhow::Result<()> {
+        let chain = fixtures::regtest_chain(3);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = RestClient::new(format!("http://{}/", listener.local_addr()?));
+        let server = spawn(serve(listener, chain.clone()));
+
+        assert_eq!(client.get_block_count().await.unwrap(), 2);
+
+        let hash = client.get_blockhash(2).await.unwrap();
+        assert_eq!(hash, to_u256(chain[2].block_hash()));
+        let block = client.get_block(&hash, 2).await.unwrap();
+        assert_eq!(block.height, 2);
+        assert_eq!(block.hash, hash);
+        assert_eq!(block.prev_hash, Some(to_u256(chain[1].block_hash())));
+
+        assert!(matches!(
+            client.get_blockhash(3).await,
+            Err(Error::Status(StatusCode::NOT_FOUND))
+        ));
+
+        server.abort();
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use std::str::FromStr;
+
 use base64::Engine;
 use bigdecimal::BigDecimal;
 use rand::prelude::random;
@@ -8,24 +10,53 @@ use reqwest::{
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Value};
 
+use crate::block;
+use crate::source::{self, BlockSource, BoxFuture};
+use crate::types::U256;
+
 pub struct RpcOptions {
     pub url: String,
     pub username: Option<String>,
     pub password: Option<String>,
+    pub format: BlockFormat,
+}
+
+/// Format blocks are requested in with `getblock`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockFormat {
+    /// Verbosity 2 JSON, slower but tolerant to Bitcoin-like forks with a different encoding.
+    #[default]
+    Verbose,
+    /// Verbosity 0 hex, decoded natively with Bitcoin consensus encoding.
+    Raw,
+}
+
+impl FromStr for BlockFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "verbose" => Ok(BlockFormat::Verbose),
+            "raw" => Ok(BlockFormat::Raw),
+            _ => Err(format!("Unknown block format: {}", value)),
+        }
+    }
 }
 
 pub struct RpcClient {
     client: Client,
     url: String,
+    format: BlockFormat,
 }
 
 /// Custom RPC client for interacting with Bitcoin Core as we aim to have wide compatibility with
 /// different Bitcoin Core versions and Bitcoin-like implementations.
 ///
 /// Data fields not needed for the index are omitted and won't be parsed.
-/// Verbose mode=2 (instead of parsing raw hex)
-/// is enabled so that we can accommodate to different Bitcoin Core versions where
+/// Verbose mode=2 (instead of parsing raw hex) is the default
+/// so that we can accommodate to different Bitcoin Core versions where
 /// fields may be added or removed.
+/// For chains that follow Bitcoin consensus encoding, `BlockFormat::Raw` is much cheaper to parse.
 impl RpcClient {
     pub fn new(options: RpcOptions) -> RpcClient {
         let client = Client::builder()
@@ -64,6 +95,7 @@ impl RpcClient {
         Self {
             client,
             url: options.url,
+            format: options.format,
         }
     }
 
@@ -96,6 +128,27 @@ impl RpcClient {
         Ok(block)
     }
 
+    pub async fn get_block_raw(&self, hash: &str) -> Result<Vec<u8>, Error> {
+        let hex: String = self.request("getblock", &json!([hash, 0])).await?;
+        Ok(hex::decode(hex)?)
+    }
+
+    /// Fetch a block in the configured `BlockFormat`, decoded into the shape consumed by `Db::push`.
+    pub async fn fetch_block(&self, hash: &str, height: u64) -> Result<block::Block, Error> {
+        match self.format {
+            BlockFormat::Verbose => Ok((*self.get_block(hash).await?).into()),
+            BlockFormat::Raw => {
+                let bytes = self.get_block_raw(hash).await?;
+                let block = block::Block::from_raw(height, &bytes)?;
+                // The hash of a raw block is computed, not reported by the node
+                if block.hash != U256::from_hex(hash) {
+                    return Err(Error::UnexpectedBlock(block.hash));
+                }
+                Ok(block)
+            }
+        }
+    }
+
     pub async fn get_blockhash(&self, height: &u64) -> Result<String, Error> {
         let hash: String = self.request("getblockhash", &json!([height])).await?;
         Ok(hash)
@@ -105,12 +158,66 @@ impl RpcClient {
         let count: u64 = self.request("getblockcount", &json!([])).await?;
         Ok(count)
     }
+
+    pub async fn get_raw_mempool(&self) -> Result<Vec<String>, Error> {
+        let txids: Vec<String> = self.request("getrawmempool", &json!([false])).await?;
+        Ok(txids)
+    }
+
+    /// `gettxoutsetinfo muhash`, at the tip or at `height` (which requires `-coinstatsindex`).
+    pub async fn get_txout_set_info(&self, height: Option<u64>) -> Result<TxOutSetInfo, Error> {
+        let params = match height {
+            Some(height) => json!(["muhash", height]),
+            None => json!(["muhash"]),
+        };
+        let info: TxOutSetInfo = self.request("gettxoutsetinfo", &params).await?;
+        Ok(info)
+    }
+
+    /// Fetch a mempool transaction in the configured `BlockFormat`.
+    pub async fn fetch_transaction(&self, txid: &str) -> Result<block::Tx, Error> {
+        match self.format {
+            BlockFormat::Verbose => {
+                let tx: Tx = self.request("getrawtransaction", &json!([txid, 1])).await?;
+                Ok(tx.into())
+            }
+            BlockFormat::Raw => {
+                let hex: String = self.request("getrawtransaction", &json!([txid, 0])).await?;
+                Ok(block::Tx::from_raw(&hex::decode(hex)?)?)
+            }
+        }
+    }
+}
+
+impl BlockSource for RpcClient {
+    fn get_blockhash(&self, height: u64) -> BoxFuture<'_, Result<U256, source::Error>> {
+        Box::pin(async move {
+            let hash = RpcClient::get_blockhash(self, &height).await?;
+            Ok(U256::from_hex(&hash))
+        })
+    }
+
+    fn get_block(
+        &self,
+        hash: U256,
+        height: u64,
+    ) -> BoxFuture<'_, Result<block::Block, source::Error>> {
+        Box::pin(async move { Ok(self.fetch_block(&hash.to_hex(), height).await?) })
+    }
+
+    fn get_block_count(&self) -> BoxFuture<'_, Result<u64, source::Error>> {
+        Box::pin(async move { Ok(RpcClient::get_block_count(self).await?) })
+    }
 }
 
 #[derive(Debug)]
 pub enum Error {
     Reqwest(reqwest::Error),
     Rpc(RpcError),
+    Hex(hex::FromHexError),
+    Decode(bitcoin::consensus::encode::Error),
+    /// `getblock` returned another block than the one requested, by its hash.
+    UnexpectedBlock(U256),
 }
 
 impl From<reqwest::Error> for Error {
@@ -119,6 +226,18 @@ impl From<reqwest::Error> for Error {
     }
 }
 
+impl From<hex::FromHexError> for Error {
+    fn from(err: hex::FromHexError) -> Error {
+        Error::Hex(err)
+    }
+}
+
+impl From<bitcoin::consensus::encode::Error> for Error {
+    fn from(err: bitcoin::consensus::encode::Error) -> Error {
+        Error::Decode(err)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RpcResponse<R> {
     pub result: Option<R>,
@@ -181,3 +300,75 @@ pub struct ScriptSig {
 pub struct ScriptPubKey {
     pub hex: String,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TxOutSetInfo {
+    pub height: u64,
+    pub bestblock: String,
+    pub muhash: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::consensus::serialize;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::task::spawn;
+
+    use crate::block::to_u256;
+    use crate::fixtures;
+
+    use super::*;
+
+    /// Stand-in for bitcoind answering every request with `block` as raw hex.
+    async fn serve(listener: TcpListener, block: bitcoin::Block) -> std::io::Result<()> {
+        let body = json!({"result": hex::encode(serialize(&block)), "error": null, "id": 0});
+        let body = body.to_string();
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = BufReader::new(stream);
+            let mut length = 0;
+            let mut line = String::new();
+            while stream.read_line(&mut line).await? > 2 {
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                line.clear();
+            }
+            stream.read_exact(&mut vec![0; length]).await?;
+
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n", body.len());
+            let stream = stream.get_mut();
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(b"Connection: close\r\n\r\n").await?;
+            stream.write_all(body.as_bytes()).await?;
+            stream.shutdown().await?;
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_block_hash() -> anyhow::Result<()> {
+        let chain = fixtures::regtest_chain(3);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = RpcClient::new(RpcOptions {
+            url: format!("http://{}/", listener.local_addr()?),
+            username: None,
+            password: None,
+            format: BlockFormat::Raw,
+        });
+        let server = spawn(serve(listener, chain[1].clone()));
+
+        let hash = to_u256(chain[1].block_hash());
+        let block = client.fetch_block(&hash.to_hex(), 1).await.unwrap();
+        assert_eq!(block.hash, hash);
+
+        let hash = to_u256(chain[2].block_hash()).to_hex();
+        assert!(matches!(
+            client.fetch_block(&hash, 2).await,
+            Err(Error::UnexpectedBlock(_))
+        ));
+
+        server.abort();
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use crate::block::SATOSHI_SCALE;
+use crate::compress::{decompress_amount, read_array, read_compact_size, read_script, read_varint};
+use crate::db::{self, Db, Utxo, UtxoKey, Vout};
+use crate::muhash::MuHash;
+use crate::source::{self, BlockSource};
+use crate::types::{U128Decimal, U256};
+
+/// Magic of the snapshot format introduced in Bitcoin Core 28, older snapshots have no header.
+const MAGIC: [u8; 5] = *b"utxo\xff";
+const VERSION: u16 = 2;
+/// Number of UTXOs written per batch when loading.
+const BATCH_SIZE: usize = 100_000;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    UnsupportedVersion(u16),
+    Source(source::Error),
+    /// The block at the given height of the source isn't the snapshot's base.
+    BaseMismatch {
+        height: u64,
+        hash: U256,
+    },
+    /// Snapshots can only be loaded into an empty database.
+    NotEmpty,
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<source::Error> for Error {
+    fn from(err: source::Error) -> Error {
+        Error::Source(err)
+    }
+}
+
+/// Header of a `dumptxoutset` snapshot.
+pub struct Metadata {
+    /// Hash of the block the UTXO set is at, in display order.
+    pub base_hash: U256,
+    pub coins: u64,
+}
+
+/// Streaming reader of the UTXO set written by Bitcoin Core's `dumptxoutset`.
+///
+/// Two formats are supported, both serialize coins as Core's chainstate does
+/// (`VARINT` height and coinbase, `CompressAmount` value, `ScriptCompression` script):
+/// - Core 28+: `utxo\xff` magic, version, network magic, base hash, count,
+///   then coins grouped by txid: `[txid][CompactSize n]([CompactSize vout][coin])*n`
+/// - Core 26 and 27: base hash and count, then `[txid][u32 vout][coin]` for each coin.
+pub struct SnapshotReader<R> {
+    reader: R,
+    metadata: Metadata,
+    grouped: bool,
+    remaining: u64,
+    /// Txid and number of coins left in the current group.
+    group: Option<(U256, u64)>,
+}
+
+impl SnapshotReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        SnapshotReader::new(BufReader::with_capacity(1 << 20, file))
+    }
+}
+
+impl<R: Read> SnapshotReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let prefix: [u8; 5] = read_array(&mut reader)?;
+        let (base_hash, grouped) = if prefix == MAGIC {
+            let version = u16::from_le_bytes(read_array(&mut reader)?);
+            if version != VERSION {
+                return Err(Error::UnsupportedVersion(version));
+            }
+            let _network_magic: [u8; 4] = read_array(&mut reader)?;
+            (read_hash(&mut reader)?, true)
+        } else {
+            let rest: [u8; 27] = read_array(&mut reader)?;
+            let mut hash = [0u8; 32];
+            hash[..5].copy_from_slice(&prefix);
+            hash[5..].copy_from_slice(&rest);
+            hash.reverse();
+            (U256::from(hash), false)
+        };
+        let coins = u64::from_le_bytes(read_array(&mut reader)?);
+
+        Ok(Self {
+            reader,
+            metadata: Metadata { base_hash, coins },
+            grouped,
+            remaining: coins,
+            group: None,
+        })
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Next UTXO of the snapshot, `height` of the key is where the coin was created.
+    fn next_utxo(&mut self) -> Result<Utxo, Error> {
+        let reader = &mut self.reader;
+        let vout = if self.grouped {
+            let (txid, left) = match self.group {
+                Some(group) if group.1 > 0 => group,
+                _ => (read_hash(reader)?, read_compact_size(reader)?),
+            };
+            self.group = Some((txid, left.saturating_sub(1)));
+            Vout {
+                txid,
+                n: read_compact_size(reader)? as u32,
+            }
+        } else {
+            Vout {
+                txid: read_hash(reader)?,
+                n: u32::from_le_bytes(read_array(reader)?),
+            }
+        };
+
+        let code = read_varint(reader)?;
+        let value = decompress_amount(read_varint(reader)?);
+        let script = read_script(reader)?;
+
+        Ok(Utxo {
+            key: UtxoKey {
+                script,
+                height: code >> 1,
+                vout,
+            },
+            coinbase: code & 1 == 1,
+            value: U128Decimal(value as u128, SATOSHI_SCALE),
+        })
+    }
+}
+
+impl<R: Read> Iterator for SnapshotReader<R> {
+    type Item = Result<Utxo, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(self.next_utxo())
+    }
+}
+
+/// Hashes are serialized in internal byte order, we store them in display order.
+fn read_hash<R: Read>(reader: &mut R) -> io::Result<U256> {
+    let mut hash: [u8; 32] = read_array(reader)?;
+    hash.reverse();
+    Ok(U256::from(hash))
+}
+
+/// Tip to set for a snapshot based at `height`, verified against the source's best chain.
+/// The height is not part of the snapshot, `dumptxoutset` reports it as `base_height`.
+pub async fn base(
+    source: &dyn BlockSource,
+    metadata: &Metadata,
+    height: u64,
+) -> Result<db::Block, Error> {
+    let hash = source.get_blockhash(height).await?;
+    if hash != metadata.base_hash {
+        return Err(Error::BaseMismatch { height, hash });
+    }
+    let prev_hash = match height.checked_sub(1) {
+        Some(prev_height) => source.get_blockhash(prev_height).await?,
+        None => U256::zero(),
+    };
+
+    Ok(db::Block {
+        height,
+        hash,
+        prev_hash,
+        muhash: MuHash::default(),
+    })
+}
+
+/// Load every UTXO of the snapshot into an empty `db` then set the tip to `base`.
+/// There is no undo data for the snapshot's base block, a reorg below it can't be disconnected.
+pub fn load<R: Read>(db: &Db, reader: SnapshotReader<R>, mut base: db::Block) -> Result<(), Error> {
+    if db.peek().is_some() {
+        return Err(Error::NotEmpty);
+    }
+    // Leftovers of an interrupted load
+    db.clear();
+
+    let coins = reader.metadata().coins;
+    let mut loaded = 0u64;
+    let mut utxos = Vec::with_capacity(BATCH_SIZE);
+    for utxo in reader {
+        let utxo = utxo?;
+        base.muhash.insert_utxo(&utxo);
+        utxos.push(utxo);
+        if utxos.len() == BATCH_SIZE {
+            loaded += utxos.len() as u64;
+            db.load_utxos(std::mem::take(&mut utxos));
+            tracing::info!("Loaded snapshot: {}/{}", loaded, coins);
+        }
+    }
+    db.load_utxos(utxos);
+
+    tracing::info!("Loaded snapshot: ({}, {})", base.height, base.hash);
+    db.load_tip(base);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TXID: [u8; 32] = [0x11; 32];
+
+    fn coin(height: u64, coinbase: bool, value: u64, script: &[u8]) -> Vec<u8> {
+        // Values that fit a single VARINT byte
+        let code = height * 2 + coinbase as u64;
+        assert!(code < 0x80 && value < 0x80 && script.len() + 6 < 0x80);
+        let mut bytes = vec![code as u8, value as u8, (script.len() + 6) as u8];
+        bytes.extend_from_slice(script);
+        bytes
+    }
+
+    #[test]
+    fn read_grouped() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&[0xfa, 0xbf, 0xb5, 0xda]);
+        bytes.extend_from_slice(&[0x22; 32]);
+        bytes.extend_from_slice(&3u64.to_le_bytes());
+        bytes.extend_from_slice(&TXID);
+        bytes.push(2);
+        bytes.push(0);
+        bytes.extend(coin(5, true, 50, &[0x51]));
+        bytes.push(3);
+        bytes.extend(coin(5, true, 9, &[0x52]));
+        bytes.extend_from_slice(&[0x33; 32]);
+        bytes.push(1);
+        bytes.push(1);
+        bytes.extend(coin(7, false, 1, &[0x53]));
+
+        let reader = SnapshotReader::new(bytes.as_slice()).unwrap();
+        assert!(reader.metadata().base_hash == U256::from([0x22; 32]));
+        assert_eq!(reader.metadata().coins, 3);
+
+        let utxos: Vec<Utxo> = reader.map(Result::unwrap).collect();
+        assert_eq!(utxos.len(), 3);
+        assert!(
+            utxos[1].key.vout
+                == Vout {
+                    txid: U256::from(TXID),
+                    n: 3
+                }
+        );
+        assert_eq!(utxos[0].key.height, 5);
+        assert!(utxos[0].coinbase);
+        assert_eq!(utxos[0].value.0, 5_000_000_000);
+        assert_eq!(utxos[1].value.0, 100_000_000);
+        assert!(
+            utxos[2].key.vout
+                == Vout {
+                    txid: U256::from([0x33; 32]),
+                    n: 1
+                }
+        );
+        assert!(!utxos[2].coinbase);
+        assert_eq!(utxos[2].key.script, vec![0x53]);
+    }
+
+    #[test]
+    fn read_legacy() {
+        let mut bytes = vec![0x22; 32];
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.extend_from_slice(&TXID);
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend(coin(9, false, 1, &[0x51]));
+
+        let mut reader = SnapshotReader::new(bytes.as_slice()).unwrap();
+        assert!(reader.metadata().base_hash == U256::from([0x22; 32]));
+        let utxo = reader.next().unwrap().unwrap();
+        assert!(
+            utxo.key.vout
+                == Vout {
+                    txid: U256::from(TXID),
+                    n: 4
+                }
+        );
+        assert_eq!(utxo.key.height, 9);
+        assert!(reader.next().is_none());
+    }
+}
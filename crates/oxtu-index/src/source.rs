@@ -0,0 +1,57 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use crate::block::Block;
+use crate::types::U256;
+use crate::{blk, p2p, rest, rpc};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Source of blocks for `Index`, e.g. the JSON-RPC, REST or P2P interface of a bitcoind or its `blk*.dat` files.
+///
+/// Only blocks of the source's best chain are returned,
+/// it is up to the index to detect forks from the `prev_hash` of the returned blocks.
+pub trait BlockSource: Send + Sync {
+    /// Hash of the block at `height` in the best chain.
+    fn get_blockhash(&self, height: u64) -> BoxFuture<'_, Result<U256, Error>>;
+
+    /// Block by hash, `height` is provided as it is not part of every block encoding.
+    fn get_block(&self, hash: U256, height: u64) -> BoxFuture<'_, Result<Block, Error>>;
+
+    /// Height of the tip of the best chain.
+    fn get_block_count(&self) -> BoxFuture<'_, Result<u64, Error>>;
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Rpc(rpc::Error),
+    Rest(rest::Error),
+    P2p(p2p::Error),
+    Blk(blk::Error),
+    /// The source has no block at the requested height or with the requested hash.
+    NotFound,
+}
+
+impl From<rpc::Error> for Error {
+    fn from(err: rpc::Error) -> Error {
+        Error::Rpc(err)
+    }
+}
+
+impl From<rest::Error> for Error {
+    fn from(err: rest::Error) -> Error {
+        Error::Rest(err)
+    }
+}
+
+impl From<p2p::Error> for Error {
+    fn from(err: p2p::Error) -> Error {
+        Error::P2p(err)
+    }
+}
+
+impl From<blk::Error> for Error {
+    fn from(err: blk::Error) -> Error {
+        Error::Blk(err)
+    }
+}
@@ -0,0 +1,252 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{watch, Notify};
+use tokio::task::{spawn, JoinHandle};
+
+#[derive(Clone)]
+pub struct ZmqOptions {
+    /// Endpoint of the bitcoind ZMQ publisher, e.g. `tcp://127.0.0.1:28332`
+    pub url: String,
+    /// Topic to subscribe to, `hashblock` or `rawblock` depending on how bitcoind is configured.
+    pub topic: String,
+}
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+/// Largest frame body accepted, notifications carry at most a block (`MAX_BLOCK_SERIALIZED_SIZE`).
+const MAX_FRAME_SIZE: u64 = 4_000_000;
+
+/// Minimal ZMTP 3.0 SUB socket for bitcoind's `-zmqpub*` notifications.
+///
+/// Only the NULL security mechanism over TCP is implemented,
+/// which is the only mechanism bitcoind supports.
+/// This allows us to receive notifications without linking against libzmq.
+///
+/// Reference:
+/// https://rfc.zeromq.org/spec/23/
+pub struct Subscriber {
+    stream: TcpStream,
+}
+
+impl Subscriber {
+    pub async fn connect(url: &str, topic: &str) -> io::Result<Subscriber> {
+        let addr = url.strip_prefix("tcp://").unwrap_or(url);
+        let mut stream = TcpStream::connect(addr).await?;
+
+        stream.write_all(&greeting()).await?;
+        let mut peer = [0u8; 64];
+        stream.read_exact(&mut peer).await?;
+        if peer[0] != 0xff || peer[9] != 0x7f || peer[10] < 3 {
+            return Err(invalid_data("Unsupported ZMTP greeting"));
+        }
+
+        write_frame(&mut stream, FLAG_COMMAND, &ready("SUB")).await?;
+        let (flags, _) = read_frame(&mut stream).await?;
+        if flags & FLAG_COMMAND == 0 {
+            return Err(invalid_data("Expected READY command"));
+        }
+
+        // ZMTP 3.0 subscriptions are sent as a message with 0x01 followed by the topic
+        let mut subscribe = vec![0x01];
+        subscribe.extend_from_slice(topic.as_bytes());
+        write_frame(&mut stream, 0, &subscribe).await?;
+
+        Ok(Subscriber { stream })
+    }
+
+    /// Receive the next multipart message, for bitcoind: `[topic, body, sequence]`
+    pub async fn recv(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        let mut parts = Vec::new();
+        loop {
+            let (flags, body) = read_frame(&mut self.stream).await?;
+            if flags & FLAG_COMMAND != 0 {
+                // Commands (e.g. PING) are not part of a message
+                continue;
+            }
+            parts.push(body);
+            if flags & FLAG_MORE == 0 {
+                return Ok(parts);
+            }
+        }
+    }
+}
+
+/// Spawn a task that calls `notify_one` on every message published for the topic.
+/// Reconnects when the connection is lost, the index falls back to polling in the meantime.
+pub fn spawn_notifier(
+    options: ZmqOptions,
+    notify: Arc<Notify>,
+    mut stop_rx: watch::Receiver<()>,
+) -> JoinHandle<()> {
+    async fn subscribe(options: &ZmqOptions, notify: &Notify) -> io::Result<()> {
+        let mut subscriber = Subscriber::connect(&options.url, &options.topic).await?;
+        tracing::info!("ZMQ subscribed: {} {}", options.url, options.topic);
+        loop {
+            subscriber.recv().await?;
+            notify.notify_one();
+        }
+    }
+
+    spawn(async move {
+        loop {
+            tokio::select! {
+                _ = stop_rx.changed() => return,
+                result = subscribe(&options, &notify) => {
+                    if let Err(error) = result {
+                        tracing::info!("ZMQ errored: {}, error: {:?}", options.url, error);
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = stop_rx.changed() => return,
+                _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+            }
+        }
+    })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn greeting() -> [u8; 64] {
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    // Version 3.0
+    greeting[10] = 3;
+    greeting[11] = 0;
+    greeting[12..16].copy_from_slice(b"NULL");
+    greeting
+}
+
+fn ready(socket_type: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.push(5);
+    body.extend_from_slice(b"READY");
+    body.push(11);
+    body.extend_from_slice(b"Socket-Type");
+    body.extend_from_slice(&(socket_type.len() as u32).to_be_bytes());
+    body.extend_from_slice(socket_type.as_bytes());
+    body
+}
+
+async fn write_frame(stream: &mut TcpStream, flags: u8, body: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(body.len() + 9);
+    if body.len() > u8::MAX as usize {
+        frame.push(flags | FLAG_LONG);
+        frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    } else {
+        frame.push(flags);
+        frame.push(body.len() as u8);
+    }
+    frame.extend_from_slice(body);
+    stream.write_all(&frame).await
+}
+
+async fn read_frame(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let flags = stream.read_u8().await?;
+    let size = if flags & FLAG_LONG != 0 {
+        stream.read_u64().await?
+    } else {
+        stream.read_u8().await? as u64
+    };
+    if size > MAX_FRAME_SIZE {
+        return Err(invalid_data("Frame too large"));
+    }
+    let mut body = vec![0u8; size as usize];
+    stream.read_exact(&mut body).await?;
+    Ok((flags, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Stand-in for bitcoind's ZMQ publisher, publishes `count` hashblock notifications.
+    async fn publisher(listener: TcpListener, count: u32) -> io::Result<Vec<u8>> {
+        let (mut stream, _) = listener.accept().await?;
+        stream.write_all(&greeting()).await?;
+        let mut peer = [0u8; 64];
+        stream.read_exact(&mut peer).await?;
+
+        read_frame(&mut stream).await?;
+        write_frame(&mut stream, FLAG_COMMAND, &ready("PUB")).await?;
+        let (_, subscription) = read_frame(&mut stream).await?;
+
+        for sequence in 0..count {
+            write_frame(&mut stream, FLAG_MORE, b"hashblock").await?;
+            write_frame(&mut stream, FLAG_MORE, &[0xab; 32]).await?;
+            write_frame(&mut stream, 0, &sequence.to_le_bytes()).await?;
+        }
+        Ok(subscription)
+    }
+
+    #[tokio::test]
+    async fn subscribe_hashblock() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("tcp://{}", listener.local_addr()?);
+        let publisher = spawn(publisher(listener, 2));
+
+        let mut subscriber = Subscriber::connect(&url, "hashblock").await?;
+        let message = subscriber.recv().await?;
+        assert_eq!(message[0], b"hashblock");
+        assert_eq!(message[1], [0xab; 32]);
+        assert_eq!(message[2], 0u32.to_le_bytes());
+
+        let message = subscriber.recv().await?;
+        assert_eq!(message[2], 1u32.to_le_bytes());
+
+        let subscription = publisher.await??;
+        assert_eq!(subscription, b"\x01hashblock");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn frame_too_large() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let peer = spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut header = vec![FLAG_LONG];
+            header.extend_from_slice(&u64::MAX.to_be_bytes());
+            stream.write_all(&header).await
+        });
+
+        let mut stream = TcpStream::connect(addr).await?;
+        peer.await??;
+        let error = read_frame(&mut stream).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notifier_wakes() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let options = ZmqOptions {
+            url: format!("tcp://{}", listener.local_addr()?),
+            topic: "hashblock".to_string(),
+        };
+        let publisher = spawn(publisher(listener, 1));
+
+        let notify = Arc::new(Notify::new());
+        let (stop_tx, stop_rx) = watch::channel(());
+        let notifier = spawn_notifier(options, notify.clone(), stop_rx);
+
+        tokio::time::timeout(Duration::from_secs(5), notify.notified()).await?;
+        publisher.await??;
+
+        stop_tx.send(())?;
+        notifier.await?;
+        Ok(())
+    }
+}
@@ -1,17 +1,30 @@
 use std::env;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
+use jsonrpsee::http_client::HttpClientBuilder;
 use jsonrpsee::server::middleware::rpc::{RpcServiceBuilder, RpcServiceT};
 use jsonrpsee::server::Server;
+use tempfile::tempdir;
 use tokio::net::ToSocketAddrs;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::watch;
 use tracing_subscriber::filter::EnvFilter;
 
-use oxtu_index::rpc::RpcOptions;
-use oxtu_index::Index;
-use service::{OxtuRpcServer, RpcServer};
+use oxtu_index::blk::BlkOptions;
+use oxtu_index::checkpoint;
+use oxtu_index::db::{self, CfOptions, Db, DbOptions};
+use oxtu_index::export;
+use oxtu_index::mempool::MempoolOptions;
+use oxtu_index::p2p::{P2pOptions, PeerClient};
+use oxtu_index::rest::RestClient;
+use oxtu_index::rpc::{RpcClient, RpcOptions};
+use oxtu_index::source::BlockSource;
+use oxtu_index::zmq::ZmqOptions;
+use oxtu_index::{Index, IndexOptions, PruneOptions, SecondaryOptions};
+use service::{OxtuRpcServer, RpcClient as _, RpcServer};
 
 mod service;
 
@@ -42,7 +55,13 @@ impl OxtuHandle {
     }
 }
 
-async fn start_oxtu(addrs: impl ToSocketAddrs, path: &str, rpc_options: RpcOptions) -> OxtuHandle {
+async fn start_oxtu(
+    addrs: impl ToSocketAddrs,
+    path: &str,
+    source: Arc<dyn BlockSource>,
+    index_options: IndexOptions,
+    checkpoint_dir: Option<PathBuf>,
+) -> OxtuHandle {
     let rpc_middleware = RpcServiceBuilder::new().layer_fn(LoggingMiddleware);
     let server = Server::builder()
         .set_rpc_middleware(rpc_middleware)
@@ -54,12 +73,18 @@ async fn start_oxtu(addrs: impl ToSocketAddrs, path: &str, rpc_options: RpcOptio
         .local_addr()
         .expect("server must have a local address");
 
-    let index = Index::open(path, rpc_options);
+    let index = Index::with_source(path, source, index_options);
 
     let (stop_tx, mut stop_rx) = watch::channel(());
 
     let index_handle = index.start();
-    let server_handle = server.start(OxtuRpcServer { index }.into_rpc());
+    let server_handle = server.start(
+        OxtuRpcServer {
+            index,
+            checkpoint_dir,
+        }
+        .into_rpc(),
+    );
 
     tokio::spawn(async move {
         stop_rx.changed().await.unwrap();
@@ -75,6 +100,190 @@ async fn start_oxtu(addrs: impl ToSocketAddrs, path: &str, rpc_options: RpcOptio
     }
 }
 
+fn block_source(rpc_client: Option<Arc<RpcClient>>) -> Arc<dyn BlockSource> {
+    match (
+        env::var("BITCOIND_REST_URL"),
+        env::var("BITCOIND_P2P_ADDR"),
+        rpc_client,
+    ) {
+        (Ok(url), _, _) => Arc::new(RestClient::new(url)),
+        (_, Ok(addr), _) => Arc::new(PeerClient::new(P2pOptions {
+            addr,
+            network: env::var("BITCOIND_P2P_NETWORK")
+                .unwrap_or_else(|_| "bitcoin".to_string())
+                .parse()
+                .expect("Unknown BITCOIND_P2P_NETWORK"),
+        })),
+        (_, _, Some(rpc_client)) => rpc_client,
+        _ => panic!("BITCOIND_RPC_URL, BITCOIND_REST_URL or BITCOIND_P2P_ADDR must be set"),
+    }
+}
+
+/// `path` is `DATABASE_PATH`, the directory of the database and of the blk files index.
+fn index_options(rpc_client: Option<Arc<RpcClient>>, path: &str) -> IndexOptions {
+    IndexOptions {
+        prefetch: env::var("INDEX_PREFETCH")
+            .map(|prefetch| prefetch.parse().expect("INDEX_PREFETCH must be a number"))
+            .unwrap_or_else(|_| IndexOptions::default().prefetch),
+        zmq: env::var("BITCOIND_ZMQ_URL").ok().map(|url| ZmqOptions {
+            url,
+            topic: env::var("BITCOIND_ZMQ_TOPIC").unwrap_or_else(|_| "hashblock".to_string()),
+        }),
+        blk: env::var("BITCOIND_BLOCKS_DIR").ok().map(|dir| BlkOptions {
+            dir: PathBuf::from(dir),
+            network: env::var("BITCOIND_BLOCKS_NETWORK")
+                .unwrap_or_else(|_| "bitcoin".to_string())
+                .parse()
+                .expect("Unknown BITCOIND_BLOCKS_NETWORK"),
+            index: Some(Path::new(path).join("blk-index")),
+        }),
+        mempool: match env::var("MEMPOOL_ENABLED").as_deref() {
+            Ok("true") => Some(MempoolOptions {
+                rpc_client: rpc_client.expect("BITCOIND_RPC_URL must be set for the mempool"),
+                interval: Duration::from_secs(
+                    env::var("MEMPOOL_POLL_INTERVAL")
+                        .map(|secs| {
+                            secs.parse()
+                                .expect("MEMPOOL_POLL_INTERVAL must be a number")
+                        })
+                        .unwrap_or(5),
+                ),
+                zmq_url: env::var("BITCOIND_ZMQ_RAWTX_URL").ok(),
+            }),
+            _ => None,
+        },
+        prune: match env::var("INDEX_PRUNE_DEPTH").as_deref() {
+            Ok("none") => None,
+            depth => {
+                let default = PruneOptions::default();
+                Some(PruneOptions {
+                    depth: depth
+                        .map(|depth| depth.parse().expect("INDEX_PRUNE_DEPTH must be a number"))
+                        .unwrap_or(default.depth),
+                    interval: env::var("INDEX_PRUNE_INTERVAL")
+                        .map(|interval| {
+                            interval
+                                .parse()
+                                .expect("INDEX_PRUNE_INTERVAL must be a number")
+                        })
+                        .unwrap_or(default.interval),
+                    compact: env::var("INDEX_PRUNE_COMPACT").as_deref() == Ok("true"),
+                })
+            }
+        },
+        recovery: env::var("INDEX_RECOVERY")
+            .map(|recovery| recovery.parse().unwrap())
+            .unwrap_or_default(),
+        cache_size: env::var("INDEX_CACHE_SIZE")
+            .map(|mib| {
+                mib.parse::<usize>()
+                    .expect("INDEX_CACHE_SIZE must be a number")
+                    << 20
+            })
+            .unwrap_or_else(|_| IndexOptions::default().cache_size),
+        secondary: env::var("DATABASE_SECONDARY_PATH")
+            .ok()
+            .map(|path| SecondaryOptions {
+                path: PathBuf::from(path),
+                interval: Duration::from_secs(
+                    env::var("DATABASE_SECONDARY_INTERVAL")
+                        .map(|secs| {
+                            secs.parse()
+                                .expect("DATABASE_SECONDARY_INTERVAL must be a number")
+                        })
+                        .unwrap_or(1),
+                ),
+            }),
+        db: db_options(),
+        query_concurrency: env::var("QUERY_CONCURRENCY")
+            .map(|queries| queries.parse().expect("QUERY_CONCURRENCY must be a number"))
+            .unwrap_or_else(|_| IndexOptions::default().query_concurrency),
+    }
+}
+
+fn db_options() -> DbOptions {
+    let default = DbOptions::default();
+    DbOptions {
+        block_cache_size: env::var("DATABASE_BLOCK_CACHE_SIZE")
+            .map(|mib| {
+                mib.parse::<usize>()
+                    .expect("DATABASE_BLOCK_CACHE_SIZE must be a number")
+                    << 20
+            })
+            .unwrap_or(default.block_cache_size),
+        max_open_files: env::var("DATABASE_MAX_OPEN_FILES")
+            .map(|files| {
+                files
+                    .parse()
+                    .expect("DATABASE_MAX_OPEN_FILES must be a number")
+            })
+            .unwrap_or(default.max_open_files),
+        cfs: db::CF_NAMES
+            .iter()
+            .map(|name| {
+                let var = |option: &str| {
+                    env::var(format!("DATABASE_{}_{}", name.to_uppercase(), option)).ok()
+                };
+                let options = CfOptions {
+                    bloom_bits: var("BLOOM_BITS").map(|bits| {
+                        bits.parse()
+                            .expect("DATABASE_*_BLOOM_BITS must be a number")
+                    }),
+                    compression: var("COMPRESSION").map(|levels| {
+                        levels
+                            .split(',')
+                            .map(|compression| compression.parse().unwrap())
+                            .collect()
+                    }),
+                    write_buffer_size: var("WRITE_BUFFER_SIZE").map(|mib| {
+                        mib.parse::<usize>()
+                            .expect("DATABASE_*_WRITE_BUFFER_SIZE must be a number")
+                            << 20
+                    }),
+                };
+                (name.to_string(), options)
+            })
+            .collect(),
+    }
+}
+
+/// Open the database read-only as a RocksDB secondary instance keeping its logs in `secondary`,
+/// caught up with the running oxtu if there is one.
+fn open_read_only(db_path: &str, secondary: &Path) -> Db {
+    let db = Db::open_secondary(db_path, secondary.to_str().unwrap(), &db_options());
+    db.try_catch_up()
+        .expect("Failed to catch up with the database");
+    db
+}
+
+/// Compare the UTXO set hash of the database with bitcoind at the same height,
+/// exits with 1 on a mismatch.
+async fn verify_muhash(db: &Db, rpc_client: &RpcClient) {
+    let tip = db.peek().expect("Nothing indexed yet");
+    let muhash = tip.muhash.finalize().to_hex();
+    let info = match rpc_client.get_txout_set_info(None).await {
+        Ok(info) if info.height == tip.height => info,
+        // Past heights require -coinstatsindex
+        _ => rpc_client
+            .get_txout_set_info(Some(tip.height))
+            .await
+            .expect("Failed to gettxoutsetinfo"),
+    };
+
+    tracing::info!("oxtu: ({}, {}), muhash: {}", tip.height, tip.hash, muhash);
+    tracing::info!(
+        "bitcoind: ({}, {}), muhash: {}",
+        info.height,
+        info.bestblock,
+        info.muhash
+    );
+    if info.bestblock != tip.hash.to_hex() || info.muhash != muhash {
+        tracing::error!("UTXO set mismatch at height {}", tip.height);
+        std::process::exit(1);
+    }
+    tracing::info!("UTXO set matches at height {}", tip.height);
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt()
@@ -85,14 +294,100 @@ async fn main() {
     let listen = env::var("OXTU_LISTEN").unwrap_or_else(|_| "127.0.0.1".to_string());
     let addrs = format!("{}:{}", listen, port);
     let path = env::var("DATABASE_PATH").unwrap_or_else(|_| "/oxtu/.oxtu".to_string());
-    let rpc_options = RpcOptions {
-        url: env::var("BITCOIND_RPC_URL").expect("BITCOIND_RPC_URL must be set"),
-        username: env::var("BITCOIND_RPC_USERNAME").ok(),
-        password: env::var("BITCOIND_RPC_PASSWORD").ok(),
-    };
+    let db_path = format!("{}/data", path);
+    let rpc_client = env::var("BITCOIND_RPC_URL").ok().map(|url| {
+        Arc::new(RpcClient::new(RpcOptions {
+            url,
+            username: env::var("BITCOIND_RPC_USERNAME").ok(),
+            password: env::var("BITCOIND_RPC_PASSWORD").ok(),
+            format: env::var("BITCOIND_RPC_BLOCK_FORMAT")
+                .map(|format| format.parse().unwrap())
+                .unwrap_or_default(),
+        }))
+    });
 
-    let db_path = path + "/data";
-    let handle = start_oxtu(addrs, &db_path, rpc_options).await;
+    // `import` and `restore` write the database directly, it must not be in use by a running oxtu.
+    // The others only read it and can run alongside.
+    match env::args().nth(1).as_deref() {
+        Some("export") => {
+            const USAGE: &str = "Usage: oxtu export <output dir> [binary|csv|ndjson]";
+            let dir = env::args().nth(2).expect(USAGE);
+            let format = env::args()
+                .nth(3)
+                .map(|format| format.parse().unwrap())
+                .unwrap_or_default();
+            let secondary = tempdir().unwrap();
+            let db = open_read_only(&db_path, secondary.path());
+            export::export(&db, Path::new(&dir), format).expect("Failed to export");
+            return;
+        }
+        Some("import") => {
+            const USAGE: &str = "Usage: oxtu import <dumptxoutset file> <base height>";
+            let file = env::args().nth(2).expect(USAGE);
+            let height = env::args()
+                .nth(3)
+                .and_then(|h| h.parse().ok())
+                .expect(USAGE);
+            let source = block_source(rpc_client.clone());
+            let index = Index::with_source(&db_path, source, index_options(rpc_client, &path));
+            index
+                .import(PathBuf::from(file), height)
+                .await
+                .expect("Failed to import snapshot");
+            tracing::info!("Imported snapshot at height {}", height);
+            return;
+        }
+        Some("verify") => {
+            let secondary = tempdir().unwrap();
+            let db = open_read_only(&db_path, secondary.path());
+            let verified = db
+                .snapshot()
+                .verify(|violation| tracing::error!("{}", violation));
+            tracing::info!("Verified: {:?}", verified);
+            if verified.violations > 0 {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some("checkpoint") => {
+            // The database is in use by the running oxtu, which checkpoints it
+            const USAGE: &str = "Usage: oxtu checkpoint <name>";
+            let name = env::args().nth(2).expect(USAGE);
+            assert_ne!(port, "0", "OXTU_PORT of the running oxtu must be set");
+            let client = HttpClientBuilder::default()
+                .request_timeout(Duration::from_secs(3600))
+                .build(format!("http://{}", addrs))
+                .unwrap();
+            let manifest = client.checkpoint(name).await.expect("Failed to checkpoint");
+            tracing::info!("Checkpoint: {:?}", manifest);
+            return;
+        }
+        Some("restore") => {
+            const USAGE: &str = "Usage: oxtu restore <checkpoint dir>";
+            let dir = env::args().nth(2).expect(USAGE);
+            checkpoint::restore(Path::new(&dir), Path::new(&db_path)).expect("Failed to restore");
+            return;
+        }
+        Some("verify-muhash") => {
+            let rpc_client = rpc_client.expect("BITCOIND_RPC_URL must be set to verify");
+            let secondary = tempdir().unwrap();
+            verify_muhash(&open_read_only(&db_path, secondary.path()), &rpc_client).await;
+            return;
+        }
+        Some(command) => panic!("Unknown command: {}", command),
+        None => {}
+    }
+
+    let source = block_source(rpc_client.clone());
+    let checkpoint_dir = env::var("CHECKPOINT_DIR").ok().map(PathBuf::from);
+    let handle = start_oxtu(
+        addrs,
+        &db_path,
+        source,
+        index_options(rpc_client, &path),
+        checkpoint_dir,
+    )
+    .await;
     tracing::info!("JSON-RPC server is running on {}", handle.addr);
 
     let mut sigint = signal(SignalKind::interrupt()).unwrap();
@@ -121,10 +416,10 @@ mod tests {
     use testcontainers::ContainerAsync;
     use tracing_test::traced_test;
 
-    use oxtu_index::rpc::RpcOptions;
+    use oxtu_index::rpc::{BlockFormat, RpcOptions};
     use testcontainers_bitcoind::{Async, Bitcoind};
 
-    use crate::service::{ListUnspentQueryOptions, RpcClient};
+    use crate::service::ListUnspentQueryOptions;
 
     use super::*;
 
@@ -179,17 +474,21 @@ mod tests {
                     url: bitcoind.rpc_url().await?,
                     username: Some(username),
                     password: Some(password),
+                    format: BlockFormat::Verbose,
                 },
                 _ => RpcOptions {
                     url: bitcoind.rpc_url().await?,
                     username: None,
                     password: None,
+                    format: BlockFormat::Verbose,
                 },
             };
             start_oxtu(
                 "127.0.0.1:0",
                 temp_dir.path().to_str().unwrap(),
-                rpc_options,
+                Arc::new(RpcClient::new(rpc_options)),
+                IndexOptions::default(),
+                None,
             )
             .await
         };
@@ -244,6 +543,30 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn list_unspent_empty() -> anyhow::Result<()> {
+        let test = self::setup().await?;
+        let address = test.get_new_address();
+        let hashes = test.generate(10, &test.get_new_address());
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+
+        let client = test.rpc_client();
+        let result = client
+            .listunspentat(address.to_string(), None)
+            .await
+            .unwrap();
+
+        // Nothing to list, the tip is still reported
+        assert!(result.utxos.is_empty());
+        assert_eq!(result.bestblock_height, 10);
+        assert_eq!(result.bestblock, hashes.last().unwrap().to_string());
+
+        test.stop().await;
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn list_unspent_range() -> anyhow::Result<()> {
@@ -1,4 +1,5 @@
 use std::env;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use bigdecimal::BigDecimal;
@@ -8,7 +9,9 @@ use jsonrpsee::proc_macros::rpc;
 use jsonrpsee::types::{ErrorCode, ErrorObjectOwned};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
 
+use oxtu_index::checkpoint::{self, Manifest};
 use oxtu_index::db::ScriptInfo;
 use oxtu_index::types::U128Decimal;
 use oxtu_index::Index;
@@ -34,16 +37,38 @@ pub struct Utxo {
     pub coinbase: bool,
 }
 
+/// UTXOs of `listunspentat`, all read at the tip it reports.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListUnspent {
+    /// Hash of the tip the UTXO set was read at, `confirmations` are counted from it.
+    pub bestblock: String,
+    pub bestblock_height: u64,
+    pub utxos: Vec<Utxo>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AddressInfo {
     pub address: String,
     #[serde(with = "bigdecimal::serde::json_num")]
     pub balance: BigDecimal,
+    /// Balance change from mempool transactions, negative when confirmed outputs are being spent.
+    #[serde(with = "bigdecimal::serde::json_num")]
+    pub unconfirmed_balance: BigDecimal,
     #[serde(with = "bigdecimal::serde::json_num")]
     pub total_sent: BigDecimal,
     #[serde(with = "bigdecimal::serde::json_num")]
     pub total_received: BigDecimal,
     pub tx_count: u64,
+    /// Hash of the tip the balances were read at.
+    pub bestblock: String,
+    pub bestblock_height: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxOutSetInfo {
+    pub height: u64,
+    pub bestblock: String,
+    pub muhash: String,
 }
 
 #[rpc(server, client)]
@@ -51,6 +76,8 @@ pub trait Rpc {
     /// RPC Method: listunspent
     /// Implements `bitcoin-core` wallet RPC method `listunspent` without indexing wallet.
     /// Instead of returning all UTXOs from a wallet, this method returns all UTXOs from an address.
+    /// With the mempool overlay enabled, UTXOs spent by mempool transactions are omitted
+    /// and unconfirmed UTXOs are included (first) with `confirmations: 0` when `minconf=0`.
     ///
     /// Reference:
     /// https://github.com/bitcoin/bitcoin/blob/538363738e9e30813cf3e76ca4f71c1aaff349e7/src/wallet/rpc/coins.cpp#L497
@@ -61,15 +88,40 @@ pub trait Rpc {
         query_options: Option<ListUnspentQueryOptions>,
     ) -> Result<Vec<Utxo>, ErrorObjectOwned>;
 
+    /// RPC Method: listunspentat
+    /// Same as `listunspent`, with the tip the UTXOs were read at, even when there are none.
+    #[method(name = "listunspentat")]
+    async fn listunspentat(
+        &self,
+        address: String,
+        query_options: Option<ListUnspentQueryOptions>,
+    ) -> Result<ListUnspent, ErrorObjectOwned>;
+
     #[method(name = "getaddressinfo")]
     async fn getaddressinfo(&self, address: String) -> Result<AddressInfo, ErrorObjectOwned>;
 
+    /// RPC Method: gettxoutsetinfo
+    /// Only `hash_type=muhash` is supported, the UTXO set hash is kept up to date on every block
+    /// and can be compared with Bitcoin Core's `gettxoutsetinfo muhash` at the same height.
+    #[method(name = "gettxoutsetinfo")]
+    async fn gettxoutsetinfo(
+        &self,
+        hash_type: Option<String>,
+    ) -> Result<TxOutSetInfo, ErrorObjectOwned>;
+
     #[method(name = "_probe")]
     async fn probe(&self, name: String) -> Result<(), ErrorObjectOwned>;
+
+    /// Admin RPC Method: _checkpoint
+    /// Back up the database in `<CHECKPOINT_DIR>/<name>` while indexing keeps running.
+    #[method(name = "_checkpoint")]
+    async fn checkpoint(&self, name: String) -> Result<Manifest, ErrorObjectOwned>;
 }
 
 pub struct OxtuRpcServer {
     pub(crate) index: Index,
+    /// Directory of the checkpoints, `_checkpoint` is disabled when unset.
+    pub(crate) checkpoint_dir: Option<PathBuf>,
 }
 
 static MAX_COUNT: Lazy<usize> = Lazy::new(|| {
@@ -79,89 +131,202 @@ static MAX_COUNT: Lazy<usize> = Lazy::new(|| {
         .unwrap()
 });
 
-#[async_trait]
-impl RpcServer for OxtuRpcServer {
-    async fn listunspent(
+/// Error of the methods reading the tip of an empty database.
+fn not_indexed() -> ErrorObjectOwned {
+    ErrorObjectOwned::owned(
+        ErrorCode::InternalError.code(),
+        "No block indexed yet",
+        None::<()>,
+    )
+}
+
+impl OxtuRpcServer {
+    /// UTXOs of `listunspent` and `listunspentat`, read at a single snapshot.
+    async fn list_unspent(
         &self,
         address: String,
         query_options: Option<ListUnspentQueryOptions>,
-    ) -> Result<Vec<Utxo>, ErrorObjectOwned> {
+    ) -> Result<ListUnspent, ErrorObjectOwned> {
         let address_parsed = Address::from_str(&address).unwrap();
         let script = address_parsed.assume_checked().script_pubkey().to_bytes();
-        let block_tip = self.index.db.peek().expect("failed to get block tip");
-
-        let lower_bound = query_options
-            .as_ref()
-            .and_then(|o| o.maxconf)
-            .map(|maxconf| {
-                block_tip
-                    .height
-                    .checked_sub(maxconf)
-                    .map(|lower| lower + 1)
-                    .unwrap_or_else(|| u64::MAX)
-            });
-
-        let upper_bound = query_options
-            .as_ref()
-            .and_then(|o| o.minconf)
-            .map(|minconf| {
-                // RocksDB upper bound is always excluded hence + 2
-                block_tip
-                    .height
-                    .checked_sub(minconf)
-                    .map(|upper| upper + 2)
-                    .unwrap_or_else(|| u64::MIN)
-            });
-
-        let utxo_iterator = self
+        let mempool = self.index.mempool.clone();
+        let result = self
             .index
-            .db
-            .iterator_script_utxo(&script, lower_bound..upper_bound);
-        let count = query_options
-            .as_ref()
-            .and_then(|o| o.count)
-            .filter(|&count| count <= *MAX_COUNT)
-            .unwrap_or_else(|| *MAX_COUNT);
-        let script_pub_key = hex::encode(script);
-
-        let utxos = utxo_iterator
-            .take(count)
-            .map(|utxo| Utxo {
-                txid: utxo.key.vout.txid.to_hex(),
-                vout: utxo.key.vout.n,
-                address: address.clone(),
-                script_pub_key: script_pub_key.clone(),
-                amount: utxo.value.into(),
-                confirmations: block_tip.height - utxo.key.height + 1,
-                height: utxo.key.height,
-                coinbase: utxo.coinbase,
+            .query(move |db| {
+                // Pinned to the tip, blocks connected meanwhile aren't seen
+                let snapshot = db.snapshot();
+                let block_tip = snapshot.peek()?;
+
+                let lower_bound = query_options
+                    .as_ref()
+                    .and_then(|o| o.maxconf)
+                    .map(|maxconf| {
+                        block_tip
+                            .height
+                            .checked_sub(maxconf)
+                            .map(|lower| lower + 1)
+                            .unwrap_or_else(|| u64::MAX)
+                    });
+
+                let upper_bound = query_options
+                    .as_ref()
+                    .and_then(|o| o.minconf)
+                    .map(|minconf| {
+                        // RocksDB upper bound is always excluded hence + 2
+                        block_tip
+                            .height
+                            .checked_sub(minconf)
+                            .map(|upper| upper + 2)
+                            .unwrap_or_else(|| u64::MIN)
+                    });
+
+                let utxo_iterator = snapshot
+                    .iterator_script_utxo(&script, lower_bound..upper_bound)
+                    .filter(|utxo| !mempool.is_spent(&utxo.key.vout));
+                let count = query_options
+                    .as_ref()
+                    .and_then(|o| o.count)
+                    .filter(|&count| count <= *MAX_COUNT)
+                    .unwrap_or_else(|| *MAX_COUNT);
+                let script_pub_key = hex::encode(&script);
+
+                let unconfirmed = match query_options.as_ref().and_then(|o| o.minconf) {
+                    Some(0) => mempool.unspent(&script),
+                    _ => Vec::new(),
+                };
+                let unconfirmed = unconfirmed
+                    .into_iter()
+                    // Mined but not yet reconciled with the mempool, already returned as confirmed
+                    .filter(|utxo| snapshot.find_utxo(&utxo.vout).is_none())
+                    .map(|utxo| Utxo {
+                        txid: utxo.vout.txid.to_hex(),
+                        vout: utxo.vout.n,
+                        address: address.clone(),
+                        script_pub_key: script_pub_key.clone(),
+                        amount: utxo.value.into(),
+                        confirmations: 0,
+                        // Not mined yet
+                        height: 0,
+                        coinbase: false,
+                    });
+
+                let utxos = unconfirmed
+                    .chain(utxo_iterator.map(|utxo| Utxo {
+                        txid: utxo.key.vout.txid.to_hex(),
+                        vout: utxo.key.vout.n,
+                        address: address.clone(),
+                        script_pub_key: script_pub_key.clone(),
+                        amount: utxo.value.into(),
+                        confirmations: block_tip.height - utxo.key.height + 1,
+                        height: utxo.key.height,
+                        coinbase: utxo.coinbase,
+                    }))
+                    .take(count)
+                    .collect();
+
+                Some(ListUnspent {
+                    bestblock: block_tip.hash.to_hex(),
+                    bestblock_height: block_tip.height,
+                    utxos,
+                })
             })
-            .collect();
+            .await;
 
-        Ok(utxos)
+        result.ok_or_else(not_indexed)
+    }
+}
+
+#[async_trait]
+impl RpcServer for OxtuRpcServer {
+    async fn listunspent(
+        &self,
+        address: String,
+        query_options: Option<ListUnspentQueryOptions>,
+    ) -> Result<Vec<Utxo>, ErrorObjectOwned> {
+        Ok(self.list_unspent(address, query_options).await?.utxos)
+    }
+
+    async fn listunspentat(
+        &self,
+        address: String,
+        query_options: Option<ListUnspentQueryOptions>,
+    ) -> Result<ListUnspent, ErrorObjectOwned> {
+        self.list_unspent(address, query_options).await
     }
 
     async fn getaddressinfo(&self, address: String) -> Result<AddressInfo, ErrorObjectOwned> {
         let address_parsed = Address::from_str(&address).unwrap();
         let script = address_parsed.assume_checked().script_pubkey().to_bytes();
-        let info = self.index.db.get_script_info(&script).unwrap_or_else(|| {
-            const {
-                ScriptInfo {
-                    script: Vec::new(),
-                    balance: U128Decimal::zero(),
-                    total_sent: U128Decimal::zero(),
-                    total_received: U128Decimal::zero(),
-                    tx_count: 0,
-                }
-            }
-        });
+        let mempool = self.index.mempool.clone();
+        let result = self
+            .index
+            .query(move |db| {
+                let snapshot = db.snapshot();
+                let tip = snapshot.peek()?;
+                let info = snapshot.get_script_info(&script).unwrap_or(
+                    const {
+                        ScriptInfo {
+                            script: Vec::new(),
+                            balance: U128Decimal::zero(),
+                            total_sent: U128Decimal::zero(),
+                            total_received: U128Decimal::zero(),
+                            tx_count: 0,
+                        }
+                    },
+                );
+                // Mempool transactions mined in the snapshot but not yet reconciled
+                // are already accounted for in the balance
+                let received: BigDecimal = mempool
+                    .unspent(&script)
+                    .into_iter()
+                    .filter(|utxo| snapshot.find_utxo(&utxo.vout).is_none())
+                    .map(|utxo| BigDecimal::from(utxo.value))
+                    .sum();
+                let sent: BigDecimal = mempool
+                    .spent(&script)
+                    .into_iter()
+                    .filter(|utxo| snapshot.find_utxo(&utxo.key.vout).is_some())
+                    .map(|utxo| BigDecimal::from(utxo.value))
+                    .sum();
+                Some((tip, info, received - sent))
+            })
+            .await;
+        let (tip, info, unconfirmed_balance) = result.ok_or_else(not_indexed)?;
 
         Ok(AddressInfo {
             address,
             balance: info.balance.into(),
+            unconfirmed_balance,
             total_sent: info.total_sent.into(),
             total_received: info.total_received.into(),
             tx_count: info.tx_count,
+            bestblock: tip.hash.to_hex(),
+            bestblock_height: tip.height,
+        })
+    }
+
+    async fn gettxoutsetinfo(
+        &self,
+        hash_type: Option<String>,
+    ) -> Result<TxOutSetInfo, ErrorObjectOwned> {
+        match hash_type.as_deref() {
+            None | Some("muhash") => {}
+            _ => return Err(ErrorCode::InvalidParams.into()),
+        }
+        let tip = self
+            .index
+            .query(|db| db.peek())
+            .await
+            .ok_or_else(not_indexed)?;
+        // The modular inverse takes a few milliseconds
+        let muhash = spawn_blocking(move || tip.muhash.finalize())
+            .await
+            .map_err(|_| ErrorObjectOwned::from(ErrorCode::InternalError))?;
+
+        Ok(TxOutSetInfo {
+            height: tip.height,
+            bestblock: tip.hash.to_hex(),
+            muhash: muhash.to_hex(),
         })
     }
 
@@ -169,7 +334,9 @@ impl RpcServer for OxtuRpcServer {
         match name.as_str() {
             "liveness" => Ok(()),
             "readiness" => match self.index.status().await {
-                Ok(status) if status.initial_indexing => Err(ErrorCode::InternalError.into()),
+                Ok(status) if status.initial_indexing || status.halted => {
+                    Err(ErrorCode::InternalError.into())
+                }
                 Ok(_) => Ok(()),
                 Err(_) => Err(ErrorCode::InternalError.into()),
             },
@@ -177,4 +344,27 @@ impl RpcServer for OxtuRpcServer {
             _ => Err(ErrorCode::InvalidParams.into()),
         }
     }
+
+    async fn checkpoint(&self, name: String) -> Result<Manifest, ErrorObjectOwned> {
+        let dir = self
+            .checkpoint_dir
+            .as_ref()
+            .ok_or_else(|| ErrorObjectOwned::from(ErrorCode::MethodNotFound))?;
+        // A single path component inside the checkpoint directory
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+            return Err(ErrorCode::InvalidParams.into());
+        }
+        let dir = dir.join(name);
+        let db = self.index.db.clone();
+        spawn_blocking(move || checkpoint::create(&db, &dir))
+            .await
+            .map_err(|_| ErrorObjectOwned::from(ErrorCode::InternalError))?
+            .map_err(|err| {
+                ErrorObjectOwned::owned(
+                    ErrorCode::InternalError.code(),
+                    format!("{:?}", err),
+                    None::<()>,
+                )
+            })
+    }
 }